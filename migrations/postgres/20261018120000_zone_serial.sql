@@ -0,0 +1,5 @@
+-- incremented on every txt change, used as the serial of the soa record
+create sequence zone_serial
+	as bigint
+	minvalue 1
+	no cycle;
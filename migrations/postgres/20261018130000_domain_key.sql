@@ -0,0 +1,13 @@
+-- tsig keys used to sign dns updates for a domain
+create table domain_key
+(
+	name varchar not null
+		constraint domain_key_pk
+			primary key,
+	algorithm varchar not null,
+	secret bytea not null,
+	domain_id char(32) not null
+		constraint domain
+			references domain
+				on delete cascade
+);
@@ -0,0 +1,3 @@
+-- der encoded ocsp response stapled to the cert, shared by all replicas
+alter table cert
+	add ocsp bytea;
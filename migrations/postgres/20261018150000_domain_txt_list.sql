@@ -0,0 +1,6 @@
+-- every proof gets its own txt record, wildcard orders need one per authorization
+-- proofs were joined by whitespace before
+alter table domain
+	alter column txt type text[] using coalesce(string_to_array(txt, ' '), '{}'),
+	alter column txt set default '{}',
+	alter column txt set not null;
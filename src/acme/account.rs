@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use super::client::Directory;
+use super::{AccountKey, DatabasePersist};
+use crate::facade::AcmeFacade;
+
+async fn stored_key<F: AcmeFacade>(
+    persist: &DatabasePersist<F>,
+    realm: &str,
+) -> Result<AccountKey> {
+    persist
+        .account_key(realm)
+        .await?
+        .ok_or_else(|| anyhow!("No acme account key is stored for {}", realm))
+}
+
+// pem encoded key for backups
+pub(crate) async fn export<F: AcmeFacade>(
+    persist: &DatabasePersist<F>,
+    realm: &str,
+) -> Result<Vec<u8>> {
+    stored_key(persist, realm).await?.to_pem()
+}
+
+// replacing a different key loses the account of the stored one so it has to be forced
+pub(crate) async fn import<F: AcmeFacade>(
+    persist: &DatabasePersist<F>,
+    realm: &str,
+    pem: &[u8],
+    force: bool,
+) -> Result<()> {
+    let key = AccountKey::from_pem(pem)?;
+    if let Some(stored) = persist.account_key(realm).await? {
+        if stored.jwk()? != key.jwk()? && !force {
+            return Err(anyhow!(
+                "A different acme account key is stored for {}, use --force to replace it",
+                realm
+            ));
+        }
+    }
+
+    persist.put_account_key(realm, &key).await
+}
+
+// the new key is staged before the server gets it and promoted once the server accepted it,
+// so a failure in between does not lose the key the account is bound to
+pub(crate) async fn rollover<F: AcmeFacade>(
+    persist: &DatabasePersist<F>,
+    directory: &Directory,
+    realm: &str,
+) -> Result<String> {
+    let key = stored_key(persist, realm).await?;
+    if let Some(pending) = persist.pending_account_key(realm).await? {
+        if pending.jwk()? != key.jwk()? {
+            // the server already accepted a staged key if it knows the account by it
+            if let Ok(account) = directory.existing_account(pending.clone()).await {
+                persist.put_account_key(realm, &pending).await?;
+                info!(
+                    realm,
+                    "Promoted the pending acme account key of a previous rollover"
+                );
+                return Ok(account.kid().to_owned());
+            }
+        }
+    }
+
+    let account = directory.existing_account(key).await?;
+    let new_key = AccountKey::generate()?;
+    persist.put_pending_account_key(realm, &new_key).await?;
+    let account = account.rollover(new_key.clone()).await?;
+    persist
+        .put_account_key(realm, &new_key)
+        .await
+        .context("The acme server accepted the new key, the next rollover stores it")?;
+
+    Ok(account.kid().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::{export, import, rollover};
+    use crate::acme::client::Directory;
+    use crate::acme::pebble::Pebble;
+    use crate::acme::{AccountKey, DatabasePersist, ACCOUNT_KIND};
+    use crate::facade::{AcmeFacade, AcmeKey, InMemoryFacade};
+
+    const REALM: &str = "mailto:admin@example.com";
+
+    // storing the account key fails while set, like a database going away during a rollover
+    #[derive(Clone, Default)]
+    struct FailingFacade {
+        facade: InMemoryFacade,
+        fail: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl AcmeFacade for FailingFacade {
+        async fn find_acme(&self, key: &AcmeKey<'_>) -> Result<Option<Vec<u8>>, sqlx::Error> {
+            self.facade.find_acme(key).await
+        }
+
+        async fn put_acme(&self, key: &AcmeKey<'_>, value: &[u8]) -> Result<(), sqlx::Error> {
+            if key.kind == ACCOUNT_KIND && self.fail.load(Ordering::SeqCst) {
+                return Err(sqlx::Error::PoolTimedOut);
+            }
+            self.facade.put_acme(key, value).await
+        }
+
+        async fn find_acme_kind(
+            &self,
+            kind: &str,
+        ) -> Result<Vec<(String, i64, Vec<u8>)>, sqlx::Error> {
+            self.facade.find_acme_kind(kind).await
+        }
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let persist = DatabasePersist::new(InMemoryFacade::default(), None);
+        let error = export(&persist, REALM).await.err().unwrap();
+        assert_eq!(
+            "No acme account key is stored for mailto:admin@example.com",
+            error.to_string()
+        );
+
+        let pem = include_bytes!("../../tests/ecdsa-p256.key");
+        import(&persist, REALM, pem, false).await.unwrap();
+        let exported = export(&persist, REALM).await.unwrap();
+        assert_eq!(
+            AccountKey::from_pem(pem).unwrap().jwk().unwrap(),
+            AccountKey::from_pem(&exported).unwrap().jwk().unwrap()
+        );
+
+        // importing the same key again is fine, a different one has to be forced
+        import(&persist, REALM, &exported, false).await.unwrap();
+        let other = include_bytes!("../../tests/sni-internal.key");
+        assert!(import(&persist, REALM, other, false).await.is_err());
+        assert_eq!(exported, export(&persist, REALM).await.unwrap());
+        import(&persist, REALM, other, true).await.unwrap();
+        assert_ne!(exported, export(&persist, REALM).await.unwrap());
+
+        let p384 = include_bytes!("../../tests/ecdsa-p384.key");
+        assert!(import(&persist, REALM, p384, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rollover_stores_new_key() {
+        let pebble = Pebble::start(None, None);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+        let persist = DatabasePersist::new(InMemoryFacade::default(), None);
+        assert!(rollover(&persist, &directory, REALM).await.is_err());
+
+        // a stored key without an account is not registered by a rollover
+        let old = AccountKey::generate().unwrap();
+        persist.put_account_key(REALM, &old).await.unwrap();
+        assert!(rollover(&persist, &directory, REALM).await.is_err());
+        assert_eq!(0, pebble.accounts());
+
+        let kid = directory
+            .account(old.clone(), Vec::new(), None)
+            .await
+            .unwrap()
+            .kid()
+            .to_owned();
+        assert_eq!(kid, rollover(&persist, &directory, REALM).await.unwrap());
+
+        let new = persist.account_key(REALM).await.unwrap().unwrap();
+        assert_ne!(old.jwk().unwrap(), new.jwk().unwrap());
+        let account = directory.existing_account(new).await.unwrap();
+        assert_eq!(kid, account.kid());
+        assert_eq!(1, pebble.accounts());
+    }
+
+    #[tokio::test]
+    async fn failed_rollover_keeps_new_key() {
+        let pebble = Pebble::start(None, None);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+        let facade = FailingFacade::default();
+        let persist = DatabasePersist::new(facade.clone(), None);
+
+        let old = AccountKey::generate().unwrap();
+        persist.put_account_key(REALM, &old).await.unwrap();
+        let kid = directory
+            .account(old.clone(), Vec::new(), None)
+            .await
+            .unwrap()
+            .kid()
+            .to_owned();
+
+        // the server accepts the new key but it can not be stored as the account key
+        facade.fail.store(true, Ordering::SeqCst);
+        assert!(rollover(&persist, &directory, REALM).await.is_err());
+        let stored = persist.account_key(REALM).await.unwrap().unwrap();
+        assert_eq!(old.jwk().unwrap(), stored.jwk().unwrap());
+        let pending = persist.pending_account_key(REALM).await.unwrap().unwrap();
+        assert_eq!(
+            kid,
+            directory
+                .existing_account(pending.clone())
+                .await
+                .unwrap()
+                .kid()
+        );
+
+        // the next rollover promotes the staged key instead of using the old one
+        facade.fail.store(false, Ordering::SeqCst);
+        assert_eq!(kid, rollover(&persist, &directory, REALM).await.unwrap());
+        let stored = persist.account_key(REALM).await.unwrap().unwrap();
+        assert_eq!(pending.jwk().unwrap(), stored.jwk().unwrap());
+        assert_eq!(1, pebble.accounts());
+    }
+}
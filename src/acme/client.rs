@@ -0,0 +1,580 @@
+use anyhow::{anyhow, Context, Result};
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use hyper::{Body, Client, Request, Response};
+use hyper_rustls::HttpsConnector;
+use parking_lot::Mutex;
+use rustls::{ClientConfig, RootCertStore};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+use super::jws::{b64, external_account_binding, AccountKey};
+use crate::config::Eab;
+
+const REPLAY_NONCE: &str = "replay-nonce";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+// used unless the server asks for a longer delay with retry-after
+const POLL_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Urls {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    key_change: String,
+    #[serde(default)]
+    meta: Meta,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    #[serde(default)]
+    external_account_required: bool,
+}
+
+// RFC 7807 problem document returned with every error
+#[derive(Deserialize, Debug, Default)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Deactivated,
+    Expired,
+    Revoked,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Order {
+    pub(crate) status: Status,
+    pub(crate) authorizations: Vec<String>,
+    pub(crate) finalize: String,
+    pub(crate) certificate: Option<String>,
+    // the order url is only sent in the location header
+    #[serde(skip)]
+    pub(crate) url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Identifier {
+    pub(crate) value: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Authorization {
+    pub(crate) status: Status,
+    pub(crate) identifier: Identifier,
+    pub(crate) challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Challenge {
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    pub(crate) url: String,
+    #[serde(default)]
+    pub(crate) token: String,
+}
+
+fn http_client(ca: Option<&[u8]>) -> Result<Client<HttpsConnector<HttpConnector>>> {
+    let mut config = ClientConfig::new();
+    config.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), e)) => {
+            warn!("Could not load every native root {}", e);
+            store
+        }
+        // a configured ca can still be enough
+        Err((None, e)) => {
+            error!("Could not load native roots {}", e);
+            RootCertStore::empty()
+        }
+    };
+    if let Some(mut ca) = ca {
+        config
+            .root_store
+            .add_pem_file(&mut ca)
+            .map_err(|_| anyhow!("Acme ca is invalid"))?;
+    }
+
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    Ok(Client::builder().build(HttpsConnector::from((http, config))))
+}
+
+fn replay_nonce(res: &Response<Body>) -> Option<String> {
+    let nonce = res.headers().get(REPLAY_NONCE)?.to_str().ok()?;
+    Some(nonce.to_owned())
+}
+
+// delay in seconds or an http date as described in RFC 7231 section 7.1.3
+fn retry_after(res: &Response<Body>) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+struct Reply {
+    location: Option<String>,
+    retry_after: Option<Duration>,
+    body: Bytes,
+}
+
+// entry point of an acme server, every account request goes through it to share the nonces
+pub struct Directory {
+    http: Client<HttpsConnector<HttpConnector>>,
+    urls: Urls,
+    nonce: Mutex<Option<String>>,
+    poll_timeout: Duration,
+}
+
+impl Directory {
+    // ca is a pem encoded root trusted in addition to the native ones
+    #[tracing::instrument(name = "Directory::from_url", skip(ca))]
+    pub async fn from_url(url: &str, ca: Option<&[u8]>) -> Result<Self> {
+        let http = http_client(ca)?;
+        let res = http.get(url.parse()?).await?;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        if !status.is_success() {
+            return Err(anyhow!("Acme directory {} returned {}", url, status));
+        }
+        let urls = serde_json::from_slice(&body)
+            .with_context(|| format!("Acme directory {} is invalid", url))?;
+
+        Ok(Directory {
+            http,
+            urls,
+            nonce: Mutex::new(None),
+            poll_timeout: DEFAULT_POLL_TIMEOUT,
+        })
+    }
+
+    // how long orders and authorizations are polled until they are given up
+    pub fn with_poll_timeout(mut self, poll_timeout: Duration) -> Self {
+        self.poll_timeout = poll_timeout;
+        self
+    }
+
+    async fn nonce(&self) -> Result<String> {
+        if let Some(nonce) = self.nonce.lock().take() {
+            return Ok(nonce);
+        }
+
+        let req = Request::head(&self.urls.new_nonce).body(Body::empty())?;
+        let res = self.http.request(req).await?;
+        replay_nonce(&res).ok_or_else(|| anyhow!("Acme server sent no nonce"))
+    }
+
+    // new accounts are signed with the jwk, all other requests with the account url
+    async fn post(
+        &self,
+        key: &AccountKey,
+        kid: Option<&str>,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<Reply> {
+        // a server may forget about a nonce, which is worth one retry
+        let mut retry = true;
+        loop {
+            let mut protected = json!({ "url": url, "nonce": self.nonce().await? });
+            match kid {
+                Some(kid) => protected["kid"] = kid.into(),
+                None => protected["jwk"] = key.jwk()?,
+            }
+            let jws = key.sign(protected, payload)?;
+
+            let req = Request::post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(jws.to_string()))?;
+            let res = self.http.request(req).await?;
+            if let Some(nonce) = replay_nonce(&res) {
+                *self.nonce.lock() = Some(nonce);
+            }
+
+            let status = res.status();
+            let location = res
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(str::to_owned);
+            let retry_after = retry_after(&res);
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            if status.is_success() {
+                return Ok(Reply {
+                    location,
+                    retry_after,
+                    body,
+                });
+            }
+
+            let problem: Problem = serde_json::from_slice(&body).unwrap_or_default();
+            if problem.kind == BAD_NONCE && retry {
+                retry = false;
+                continue;
+            }
+            return Err(anyhow!(
+                "Acme request to {} failed with {} {} {}",
+                url,
+                status,
+                problem.kind,
+                problem.detail
+            ));
+        }
+    }
+
+    // registers the key or finds the account it already belongs to
+    #[tracing::instrument(name = "Directory::account", skip(self, key, eab))]
+    pub(crate) async fn account(
+        &self,
+        key: AccountKey,
+        contact: Vec<String>,
+        eab: Option<&Eab>,
+    ) -> Result<Account<'_>> {
+        let url = &self.urls.new_account;
+        let mut payload = json!({ "contact": contact, "termsOfServiceAgreed": true });
+        match eab {
+            Some(eab) => {
+                let binding = external_account_binding(&eab.kid, &eab.hmac_key, &key.jwk()?, url)?;
+                payload["externalAccountBinding"] = binding;
+            }
+            None if self.urls.meta.external_account_required => {
+                return Err(anyhow!(
+                    "Acme server requires an external account binding, configure acme.eab"
+                ))
+            }
+            None => {}
+        }
+
+        self.new_account(key, &payload).await
+    }
+
+    // finds the account of the key without registering a new one
+    #[tracing::instrument(name = "Directory::existing_account", skip(self, key))]
+    pub(crate) async fn existing_account(&self, key: AccountKey) -> Result<Account<'_>> {
+        let payload = json!({ "onlyReturnExisting": true });
+        self.new_account(key, &payload).await
+    }
+
+    async fn new_account(&self, key: AccountKey, payload: &Value) -> Result<Account<'_>> {
+        let reply = self
+            .post(&key, None, &self.urls.new_account, Some(payload))
+            .await?;
+        let kid = reply
+            .location
+            .ok_or_else(|| anyhow!("Acme server sent no account url"))?;
+
+        Ok(Account {
+            directory: self,
+            key,
+            kid,
+        })
+    }
+}
+
+pub(crate) struct Account<'a> {
+    directory: &'a Directory,
+    key: AccountKey,
+    kid: String,
+}
+
+impl<'a> Account<'a> {
+    async fn post(&self, url: &str, payload: Option<&Value>) -> Result<Reply> {
+        self.directory
+            .post(&self.key, Some(&self.kid), url, payload)
+            .await
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<T> {
+        let reply = self.post(url, payload).await?;
+        serde_json::from_slice(&reply.body)
+            .with_context(|| format!("Acme response of {} is invalid", url))
+    }
+
+    pub(crate) async fn new_order(&self, names: &[String]) -> Result<Order> {
+        let identifiers = names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect::<Vec<_>>();
+        let payload = json!({ "identifiers": identifiers });
+
+        let url = &self.directory.urls.new_order;
+        let reply = self.post(url, Some(&payload)).await?;
+        let mut order: Order = serde_json::from_slice(&reply.body)
+            .with_context(|| format!("Acme response of {} is invalid", url))?;
+        order.url = reply
+            .location
+            .ok_or_else(|| anyhow!("Acme server sent no order url"))?;
+
+        Ok(order)
+    }
+
+    pub(crate) async fn authorization(&self, url: &str) -> Result<Authorization> {
+        self.post_json(url, None).await
+    }
+
+    pub(crate) fn dns_proof(&self, challenge: &Challenge) -> Result<String> {
+        self.key.dns_proof(&challenge.token)
+    }
+
+    // tells the server the proof is in place
+    pub(crate) async fn validate(&self, challenge: &Challenge) -> Result<()> {
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        Ok(())
+    }
+
+    // polls the url until done or the poll timeout passed, the last response is returned either way
+    async fn poll<T, F>(&self, url: &str, done: F) -> Result<(T, bool)>
+    where
+        T: DeserializeOwned,
+        F: Fn(&T) -> bool,
+    {
+        let deadline = Instant::now() + self.directory.poll_timeout;
+        loop {
+            let reply = self.post(url, None).await?;
+            let value = serde_json::from_slice(&reply.body)
+                .with_context(|| format!("Acme response of {} is invalid", url))?;
+            let now = Instant::now();
+            let done = done(&value);
+            if done || now >= deadline {
+                return Ok((value, done));
+            }
+
+            // a server may ask for a longer delay, but not for one past the timeout
+            let delay = reply
+                .retry_after
+                .map_or(POLL_DELAY, |delay| delay.max(POLL_DELAY));
+            tokio::time::sleep(delay.min(deadline - now)).await;
+        }
+    }
+
+    // waits until the server is done with the challenges of the authorization
+    pub(crate) async fn wait_authorization(&self, url: &str) -> Result<Authorization> {
+        let (authorization, done) = self
+            .poll(url, |authorization: &Authorization| {
+                authorization.status != Status::Pending
+            })
+            .await?;
+        if !done {
+            return Err(anyhow!("Acme authorization {} is still pending", url));
+        }
+
+        Ok(authorization)
+    }
+
+    // waits until the order is no longer pending or processing
+    pub(crate) async fn wait_order(&self, url: &str) -> Result<Order> {
+        let (mut order, done) = self
+            .poll(url, |order: &Order| {
+                order.status != Status::Pending && order.status != Status::Processing
+            })
+            .await?;
+        if !done {
+            return Err(anyhow!("Acme order {} is still processing", url));
+        }
+
+        order.url = url.to_owned();
+        Ok(order)
+    }
+
+    pub(crate) async fn finalize(&self, order: &Order, csr: &[u8]) -> Result<Order> {
+        let payload = json!({ "csr": b64(csr) });
+        let mut finalized: Order = self.post_json(&order.finalize, Some(&payload)).await?;
+        finalized.url = order.url.clone();
+        Ok(finalized)
+    }
+
+    // pem encoded chain of the issued certificate
+    pub(crate) async fn certificate(&self, url: &str) -> Result<String> {
+        let reply = self.post(url, None).await?;
+        Ok(String::from_utf8(reply.body.to_vec())?)
+    }
+
+    pub(crate) fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    // replaces the key of the account as described in RFC 8555 section 7.3.5
+    pub(crate) async fn rollover(self, key: AccountKey) -> Result<Account<'a>> {
+        let url = &self.directory.urls.key_change;
+        // the inner jws proves possession of the new key and carries no nonce
+        let inner = key.sign(
+            json!({ "jwk": key.jwk()?, "url": url }),
+            Some(&json!({ "account": self.kid, "oldKey": self.key.jwk()? })),
+        )?;
+        self.post(url, Some(&inner)).await?;
+
+        Ok(Account {
+            directory: self.directory,
+            key,
+            kid: self.kid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Directory;
+    use crate::acme::pebble::Pebble;
+    use crate::acme::AccountKey;
+    use crate::config::Eab;
+
+    fn eab(hmac_key: &[u8]) -> Eab {
+        Eab {
+            kid: "kid-1".to_owned(),
+            hmac_key: hmac_key.to_vec(),
+        }
+    }
+
+    fn contact() -> Vec<String> {
+        vec!["mailto:admin@example.com".to_owned()]
+    }
+
+    #[tokio::test]
+    async fn account_with_external_account_binding() {
+        let pebble = Pebble::start(Some(eab(b"secret")), None);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+        let key = AccountKey::generate().unwrap();
+
+        let account = directory
+            .account(key.clone(), contact(), Some(&eab(b"secret")))
+            .await
+            .unwrap();
+        assert_eq!(1, pebble.accounts());
+
+        // the same key finds its account again, even after the server lost its nonces
+        pebble.forget_nonces();
+        let found = directory
+            .account(key, contact(), Some(&eab(b"secret")))
+            .await
+            .unwrap();
+        assert_eq!(account.kid, found.kid);
+        assert_eq!(1, pebble.accounts());
+    }
+
+    #[tokio::test]
+    async fn account_binding_is_checked() {
+        let pebble = Pebble::start(Some(eab(b"secret")), None);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+
+        let key = AccountKey::generate().unwrap();
+        let error = directory
+            .account(key.clone(), contact(), Some(&eab(b"wrong")))
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("unauthorized"), "{}", error);
+
+        let error = directory.account(key, contact(), None).await.err().unwrap();
+        assert!(error.to_string().contains("acme.eab"), "{}", error);
+        assert_eq!(0, pebble.accounts());
+    }
+
+    #[tokio::test]
+    async fn account_without_binding() {
+        let pebble = Pebble::start(None, None);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+
+        let key = AccountKey::generate().unwrap();
+        directory.account(key, Vec::new(), None).await.unwrap();
+        assert_eq!(1, pebble.accounts());
+        assert!(Directory::from_url("http://127.0.0.1:1/dir", None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rollover_keeps_account() {
+        let pebble = Pebble::start(None, None);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+        let old = AccountKey::generate().unwrap();
+        let new = AccountKey::generate().unwrap();
+
+        let error = directory.existing_account(old.clone()).await.err().unwrap();
+        assert!(
+            error.to_string().contains("accountDoesNotExist"),
+            "{}",
+            error
+        );
+        assert_eq!(0, pebble.accounts());
+
+        let account = directory
+            .account(old.clone(), contact(), None)
+            .await
+            .unwrap();
+        let kid = account.kid().to_owned();
+        let account = account.rollover(new.clone()).await.unwrap();
+        assert_eq!(kid, account.kid());
+
+        // the account now belongs to the new key
+        let found = directory.existing_account(new.clone()).await.unwrap();
+        assert_eq!(kid, found.kid());
+        assert!(directory.existing_account(old).await.is_err());
+        assert_eq!(1, pebble.accounts());
+
+        // a key can only belong to one account
+        let other = directory
+            .account(AccountKey::generate().unwrap(), contact(), None)
+            .await
+            .unwrap();
+        let error = other.rollover(new).await.err().unwrap();
+        assert!(error.to_string().contains("409"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn polling_honours_retry_after() {
+        let pebble = Pebble::start(None, None);
+        pebble.retry_after(2);
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap()
+            .with_poll_timeout(Duration::from_secs(2));
+        let key = AccountKey::generate().unwrap();
+        let account = directory.account(key, contact(), None).await.unwrap();
+
+        // the order stays pending without a validated challenge
+        let order = account
+            .new_order(&["acme.example.com".to_owned()])
+            .await
+            .unwrap();
+        let error = account.wait_order(&order.url).await.err().unwrap();
+        assert!(error.to_string().contains("still processing"), "{}", error);
+        // once right away and once two seconds later, when the timeout passed
+        assert_eq!(2, pebble.order_polls());
+    }
+}
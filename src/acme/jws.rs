@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Result};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use ring::hmac;
+use serde_json::{json, Value};
+
+// p-256 coordinates and signature halves are always 32 bytes
+const COORDINATE_LEN: i32 = 32;
+
+pub(super) fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+// flattened json serialization as required by RFC 8555 section 6.2
+fn flattened(
+    protected: &Value,
+    payload: &str,
+    signature: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Value> {
+    let protected = b64(protected.to_string().as_bytes());
+    let signature = signature(format!("{}.{}", protected, payload).as_bytes())?;
+
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": b64(&signature),
+    }))
+}
+
+// p-256 key of the acme account, the same kind of key acme-lib used to persist
+#[derive(Clone)]
+pub(crate) struct AccountKey(EcKey<Private>);
+
+impl AccountKey {
+    pub(crate) fn generate() -> Result<Self> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        Ok(AccountKey(EcKey::generate(&group)?))
+    }
+
+    // accepts SEC1 and PKCS#8 keys like the ones written by lego or certmagic
+    pub(crate) fn from_pem(pem: &[u8]) -> Result<Self> {
+        let key = PKey::private_key_from_pem(pem)?
+            .ec_key()
+            .map_err(|_| anyhow!("Account key is not an EC key"))?;
+        // the only algorithm we sign with is ES256
+        if key.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+            return Err(anyhow!("Account key is not a P-256 key"));
+        }
+        Ok(AccountKey(key))
+    }
+
+    pub(crate) fn to_pem(&self) -> Result<Vec<u8>> {
+        Ok(self.0.private_key_to_pem()?)
+    }
+
+    pub(super) fn jwk(&self) -> Result<Value> {
+        let mut ctx = BigNumContext::new()?;
+        let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+        self.0
+            .public_key()
+            .affine_coordinates_gfp(self.0.group(), &mut x, &mut y, &mut ctx)?;
+
+        // members in lexicographic order so the thumbprint can be computed from the serialization
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&x.to_vec_padded(COORDINATE_LEN)?),
+            "y": b64(&y.to_vec_padded(COORDINATE_LEN)?),
+        }))
+    }
+
+    // RFC 7638 thumbprint used in key authorizations
+    pub(super) fn thumbprint(&self) -> Result<String> {
+        Ok(b64(&sha256(self.jwk()?.to_string().as_bytes())))
+    }
+
+    // value of the _acme-challenge TXT record for a dns-01 challenge
+    pub(super) fn dns_proof(&self, token: &str) -> Result<String> {
+        let authorization = format!("{}.{}", token, self.thumbprint()?);
+        Ok(b64(&sha256(authorization.as_bytes())))
+    }
+
+    // payload None results in a POST-as-GET request
+    pub(super) fn sign(&self, protected: Value, payload: Option<&Value>) -> Result<Value> {
+        let mut protected = protected;
+        protected["alg"] = "ES256".into();
+        let payload = payload.map(|payload| b64(payload.to_string().as_bytes()));
+
+        flattened(&protected, payload.as_deref().unwrap_or_default(), |data| {
+            let signature = EcdsaSig::sign(&sha256(data), &self.0)?;
+            let mut signature_bytes = signature.r().to_vec_padded(COORDINATE_LEN)?;
+            signature_bytes.extend(signature.s().to_vec_padded(COORDINATE_LEN)?);
+            Ok(signature_bytes)
+        })
+    }
+}
+
+// binds the account key to an account the ca already knows, RFC 8555 section 7.3.4
+pub(super) fn external_account_binding(
+    kid: &str,
+    hmac_key: &[u8],
+    jwk: &Value,
+    url: &str,
+) -> Result<Value> {
+    let protected = json!({ "alg": "HS256", "kid": kid, "url": url });
+    let payload = b64(jwk.to_string().as_bytes());
+
+    flattened(&protected, &payload, |data| {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, hmac_key);
+        Ok(hmac::sign(&key, data).as_ref().to_vec())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::bn::BigNum;
+    use openssl::ec::EcKey;
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::sha::sha256;
+    use ring::hmac;
+    use serde_json::json;
+
+    use super::{b64, external_account_binding, AccountKey};
+
+    fn decode(data: &serde_json::Value) -> Vec<u8> {
+        base64::decode_config(data.as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    #[test]
+    fn account_key_pem_roundtrip() {
+        let key = AccountKey::generate().unwrap();
+        let parsed = AccountKey::from_pem(&key.to_pem().unwrap()).unwrap();
+        assert_eq!(key.jwk().unwrap(), parsed.jwk().unwrap());
+        assert_eq!(43, key.thumbprint().unwrap().len());
+        assert!(AccountKey::from_pem(b"WRONG").is_err());
+
+        // PKCS#8 works as well but only for p-256
+        assert!(AccountKey::from_pem(include_bytes!("../../tests/sni-internal.key")).is_ok());
+        let error = AccountKey::from_pem(include_bytes!("../../tests/ecdsa-p384.key"))
+            .err()
+            .unwrap();
+        assert_eq!("Account key is not a P-256 key", error.to_string());
+        let error = AccountKey::from_pem(include_bytes!("../../tests/rsa-pkcs8.key"))
+            .err()
+            .unwrap();
+        assert_eq!("Account key is not an EC key", error.to_string());
+    }
+
+    #[test]
+    fn signature_verifies() {
+        let key = AccountKey::generate().unwrap();
+        let payload = json!({ "termsOfServiceAgreed": true });
+        let jws = key
+            .sign(
+                json!({ "url": "http://acme/new-account", "nonce": "1" }),
+                Some(&payload),
+            )
+            .unwrap();
+
+        let protected: serde_json::Value =
+            serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+        assert_eq!("ES256", protected["alg"]);
+        assert_eq!(
+            payload,
+            serde_json::from_slice::<serde_json::Value>(&decode(&jws["payload"])).unwrap()
+        );
+
+        let signature = decode(&jws["signature"]);
+        assert_eq!(64, signature.len());
+        let r = BigNum::from_slice(&signature[..32]).unwrap();
+        let s = BigNum::from_slice(&signature[32..]).unwrap();
+        let signature = EcdsaSig::from_private_components(r, s).unwrap();
+        let data = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let public = EcKey::from_public_key(key.0.group(), key.0.public_key()).unwrap();
+        assert!(signature.verify(&sha256(data.as_bytes()), &public).unwrap());
+
+        // post as get has an empty payload
+        let jws = key.sign(json!({}), None).unwrap();
+        assert_eq!("", jws["payload"]);
+    }
+
+    #[test]
+    fn external_account_binding_is_hmac() {
+        let key = AccountKey::generate().unwrap();
+        let jwk = key.jwk().unwrap();
+        let eab =
+            external_account_binding("kid-1", b"secret", &jwk, "http://acme/new-account").unwrap();
+
+        let protected: serde_json::Value =
+            serde_json::from_slice(&decode(&eab["protected"])).unwrap();
+        assert_eq!(
+            json!({ "alg": "HS256", "kid": "kid-1", "url": "http://acme/new-account" }),
+            protected
+        );
+        assert_eq!(b64(jwk.to_string().as_bytes()), eab["payload"]);
+
+        let data = format!(
+            "{}.{}",
+            eab["protected"].as_str().unwrap(),
+            eab["payload"].as_str().unwrap()
+        );
+        let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        assert!(hmac::verify(&hmac_key, data.as_bytes(), &decode(&eab["signature"])).is_ok());
+    }
+}
@@ -0,0 +1,764 @@
+// in process stand-in for pebble, the test acme server of letsencrypt
+// it checks requests as strictly as pebble does so the client is tested against the RFC
+// dns challenges are validated by asking the configured dns server like a real ca would
+use anyhow::{anyhow, Result};
+use hyper::header::{CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509Req, X509};
+use parking_lot::Mutex;
+use ring::hmac;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_server::proto::rr::{Name, RData, RecordType};
+
+use super::jws::b64;
+use crate::config::Eab;
+
+// certificates are issued by the test ca
+const CA_CERT: &[u8] = include_bytes!("../../tests/ca.crt");
+const CA_KEY: &[u8] = include_bytes!("../../tests/ca.key");
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Problem {
+    status: StatusCode,
+    kind: &'static str,
+    detail: String,
+}
+
+fn problem(status: StatusCode, kind: &'static str, detail: impl Into<String>) -> Problem {
+    Problem {
+        status,
+        kind,
+        detail: detail.into(),
+    }
+}
+
+fn malformed(detail: impl Into<String>) -> Problem {
+    problem(StatusCode::BAD_REQUEST, "malformed", detail)
+}
+
+fn unauthorized(detail: impl Into<String>) -> Problem {
+    problem(StatusCode::UNAUTHORIZED, "unauthorized", detail)
+}
+
+fn not_found() -> Problem {
+    problem(StatusCode::NOT_FOUND, "malformed", "Unknown path")
+}
+
+struct Reply {
+    status: StatusCode,
+    location: Option<String>,
+    retry_after: Option<u64>,
+    content_type: &'static str,
+    body: String,
+}
+
+fn reply(status: StatusCode, location: Option<String>, body: Value) -> Reply {
+    Reply {
+        status,
+        location,
+        retry_after: None,
+        content_type: "application/json",
+        body: body.to_string(),
+    }
+}
+
+fn decode(data: &Value) -> Result<Vec<u8>, Problem> {
+    let data = data
+        .as_str()
+        .ok_or_else(|| malformed("Jws member is not a string"))?;
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| malformed("Jws member is not base64url"))
+}
+
+fn decode_json(data: &Value) -> Result<Value, Problem> {
+    serde_json::from_slice(&decode(data)?).map_err(|_| malformed("Jws member is not json"))
+}
+
+fn verify_es256(jwk: &Value, data: &[u8], signature: &[u8]) -> bool {
+    let verify = || -> Result<bool> {
+        let coordinate = |name: &str| -> Result<BigNum> {
+            let coordinate = jwk[name].as_str().unwrap_or_default();
+            let coordinate = base64::decode_config(coordinate, base64::URL_SAFE_NO_PAD)?;
+            Ok(BigNum::from_slice(&coordinate)?)
+        };
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let (x, y) = (coordinate("x")?, coordinate("y")?);
+        let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+        if signature.len() != 64 {
+            return Ok(false);
+        }
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32])?,
+            BigNum::from_slice(&signature[32..])?,
+        )?;
+        Ok(signature.verify(&sha256(data), &key)?)
+    };
+
+    verify().unwrap_or(false)
+}
+
+// RFC 8555 section 8.1
+fn key_authorization(token: &str, jwk: &Value) -> String {
+    let jwk = json!({ "crv": jwk["crv"], "kty": jwk["kty"], "x": jwk["x"], "y": jwk["y"] });
+    let thumbprint = b64(&sha256(jwk.to_string().as_bytes()));
+    b64(&sha256(format!("{}.{}", token, thumbprint).as_bytes()))
+}
+
+async fn lookup_txt(dns: SocketAddr, name: &str) -> Result<Vec<String>> {
+    let mut message = Message::new();
+    message
+        .set_id(1)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(Name::from_str(name)?, RecordType::TXT));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    socket.send_to(&message.to_vec()?, dns).await?;
+    let mut buf = vec![0; 4096];
+    let (len, _) = tokio::time::timeout(DNS_TIMEOUT, socket.recv_from(&mut buf)).await??;
+
+    let response = Message::from_vec(&buf[..len])?;
+    let txt = response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.rdata() {
+            RData::TXT(txt) => Some(
+                txt.iter()
+                    .map(|data| String::from_utf8_lossy(data))
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .collect();
+
+    Ok(txt)
+}
+
+// the certificate gets the names of the order, the csr only has to be signed by its key
+fn issue(csr: &X509Req, names: &[String]) -> Result<String> {
+    let ca = X509::from_pem(CA_CERT)?;
+    let ca_key = PKey::private_key_from_pem(CA_KEY)?;
+    let public = csr.public_key()?;
+    if !csr.verify(&public)? {
+        return Err(anyhow!("Csr signature is invalid"));
+    }
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(csr.subject_name())?;
+    builder.set_issuer_name(ca.subject_name())?;
+    builder.set_pubkey(&public)?;
+    let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(90)?);
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+
+    let mut san = SubjectAlternativeName::new();
+    for name in names {
+        san.dns(name);
+    }
+    let san = san.build(&builder.x509v3_context(Some(&ca), None))?;
+    builder.append_extension(san)?;
+    builder.sign(&ca_key, MessageDigest::sha256())?;
+
+    let mut chain = builder.build().to_pem()?;
+    chain.extend(ca.to_pem()?);
+    Ok(String::from_utf8(chain)?)
+}
+
+// verified request, payload is null for POST-as-GET
+struct Jws {
+    protected: Value,
+    payload: Value,
+    jwk: Value,
+    // index of the account if the request was signed with a kid
+    account: Option<usize>,
+}
+
+struct Authorization {
+    account: usize,
+    // name without the wildcard label
+    name: String,
+    wildcard: bool,
+    token: String,
+    status: &'static str,
+}
+
+struct Order {
+    account: usize,
+    names: Vec<String>,
+    authorizations: Vec<usize>,
+    status: &'static str,
+    certificate: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    eab: Option<Eab>,
+    dns: Option<SocketAddr>,
+    next_nonce: u64,
+    nonces: HashSet<String>,
+    // jwk of every account, the index is part of the account url
+    accounts: Vec<Value>,
+    orders: Vec<Order>,
+    authorizations: Vec<Authorization>,
+    // sent with orders which are not done yet
+    retry_after: Option<u64>,
+    order_polls: usize,
+}
+
+impl State {
+    fn nonce(&mut self) -> String {
+        self.next_nonce += 1;
+        let nonce = format!("nonce-{}", self.next_nonce);
+        self.nonces.insert(nonce.clone());
+        nonce
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Pebble {
+    url: Arc<str>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Pebble {
+    // requires an external account binding with the key if one is set
+    // dns challenges are looked up at dns, without one every challenge fails
+    pub(crate) fn start(eab: Option<Eab>, dns: Option<SocketAddr>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let pebble = Pebble {
+            url: format!("http://{}", listener.local_addr().unwrap()).into(),
+            state: Arc::new(Mutex::new(State {
+                eab,
+                dns,
+                ..Default::default()
+            })),
+        };
+
+        let service = pebble.clone();
+        let make_service = make_service_fn(move |_| {
+            let pebble = service.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| pebble.clone().handle(req))) }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+
+        pebble
+    }
+
+    pub(crate) fn directory(&self) -> String {
+        format!("{}/dir", self.url)
+    }
+
+    pub(crate) fn accounts(&self) -> usize {
+        self.state.lock().accounts.len()
+    }
+
+    pub(crate) fn orders(&self) -> usize {
+        self.state.lock().orders.len()
+    }
+
+    pub(crate) fn order_polls(&self) -> usize {
+        self.state.lock().order_polls
+    }
+
+    pub(crate) fn retry_after(&self, secs: u64) {
+        self.state.lock().retry_after = Some(secs);
+    }
+
+    // the server restarted and lost every nonce it handed out
+    pub(crate) fn forget_nonces(&self) {
+        self.state.lock().nonces.clear();
+    }
+
+    fn url(&self, resource: &str, index: usize) -> String {
+        format!("{}/{}/{}", self.url, resource, index)
+    }
+
+    async fn handle(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+        let reply = self.route(&parts.method, parts.uri.path(), &body).await;
+
+        let mut res = Response::builder().header("replay-nonce", self.state.lock().nonce());
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(problem) => Reply {
+                status: problem.status,
+                location: None,
+                retry_after: None,
+                content_type: "application/problem+json",
+                body: json!({
+                    "type": format!("urn:ietf:params:acme:error:{}", problem.kind),
+                    "detail": problem.detail,
+                })
+                .to_string(),
+            },
+        };
+        if let Some(location) = reply.location {
+            res = res.header(LOCATION, location);
+        }
+        if let Some(retry_after) = reply.retry_after {
+            res = res.header(RETRY_AFTER, retry_after);
+        }
+        let res = res
+            .status(reply.status)
+            .header(CONTENT_TYPE, reply.content_type)
+            .body(Body::from(reply.body));
+
+        Ok(res.unwrap())
+    }
+
+    async fn route(&self, method: &Method, path: &str, body: &[u8]) -> Result<Reply, Problem> {
+        match (method, path) {
+            (&Method::GET, "/dir") => {
+                return Ok(reply(StatusCode::OK, None, self.directory_json()))
+            }
+            (&Method::HEAD, "/nonce") => return Ok(reply(StatusCode::OK, None, Value::Null)),
+            (&Method::POST, _) => {}
+            _ => return Err(not_found()),
+        }
+
+        let jws = self.verify(path, body)?;
+        if path == "/new-account" {
+            return self.new_account(jws);
+        }
+        let account = jws
+            .account
+            .ok_or_else(|| malformed("Request has to be signed with a kid"))?;
+        if path == "/new-order" {
+            return self.new_order(account, &jws.payload);
+        }
+        if path == "/key-change" {
+            return self.key_change(account, &jws);
+        }
+
+        let (resource, index) = path
+            .rsplit_once('/')
+            .and_then(|(resource, index)| Some((resource, index.parse::<usize>().ok()?)))
+            .ok_or_else(not_found)?;
+        match resource {
+            "/order" => self.order(account, index),
+            "/authz" => self.authorization(account, index),
+            "/chall" => self.challenge(account, index, &jws.jwk).await,
+            "/finalize" => self.finalize(account, index, &jws.payload),
+            "/cert" => self.certificate(account, index),
+            _ => Err(not_found()),
+        }
+    }
+
+    fn directory_json(&self) -> Value {
+        json!({
+            "newNonce": format!("{}/nonce", self.url),
+            "newAccount": format!("{}/new-account", self.url),
+            "newOrder": format!("{}/new-order", self.url),
+            "keyChange": format!("{}/key-change", self.url),
+            "meta": { "externalAccountRequired": self.state.lock().eab.is_some() },
+        })
+    }
+
+    // checks nonce, url and signature of a request as described in RFC 8555 section 6.2
+    fn verify(&self, path: &str, body: &[u8]) -> Result<Jws, Problem> {
+        let mut state = self.state.lock();
+        let jws: Value = serde_json::from_slice(body).map_err(|_| malformed("Body is no jws"))?;
+        let protected = decode_json(&jws["protected"])?;
+
+        let nonce = protected["nonce"].as_str().unwrap_or_default();
+        if !state.nonces.remove(nonce) {
+            return Err(problem(
+                StatusCode::BAD_REQUEST,
+                "badNonce",
+                "Unknown nonce",
+            ));
+        }
+        if protected["url"] != format!("{}{}", self.url, path).as_str() {
+            return Err(unauthorized("Wrong url"));
+        }
+        if protected["alg"] != "ES256" {
+            return Err(problem(
+                StatusCode::BAD_REQUEST,
+                "badSignatureAlgorithm",
+                "Only ES256 is supported",
+            ));
+        }
+
+        let (jwk, account) = match (&protected["jwk"], protected["kid"].as_str()) {
+            (Value::Null, Some(kid)) => {
+                let account = kid
+                    .strip_prefix(&format!("{}/account/", self.url))
+                    .and_then(|index| index.parse::<usize>().ok())
+                    .filter(|index| *index < state.accounts.len())
+                    .ok_or_else(|| {
+                        problem(
+                            StatusCode::BAD_REQUEST,
+                            "accountDoesNotExist",
+                            "Unknown kid",
+                        )
+                    })?;
+                (state.accounts[account].clone(), Some(account))
+            }
+            (jwk @ Value::Object(_), None) => (jwk.clone(), None),
+            _ => return Err(malformed("Jws needs either a jwk or a kid")),
+        };
+
+        let payload = jws["payload"].as_str().unwrap_or_default();
+        let data = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap_or_default(),
+            payload
+        );
+        if !verify_es256(&jwk, data.as_bytes(), &decode(&jws["signature"])?) {
+            return Err(malformed("Signature is invalid"));
+        }
+        let payload = match payload {
+            "" => Value::Null,
+            _ => decode_json(&jws["payload"])?,
+        };
+
+        Ok(Jws {
+            protected,
+            payload,
+            jwk,
+            account,
+        })
+    }
+
+    // RFC 8555 section 7.3.4
+    fn verify_binding(&self, eab: &Eab, binding: &Value, jws: &Jws) -> Result<(), Problem> {
+        let protected = decode_json(&binding["protected"])?;
+        if protected["alg"] != "HS256" || protected["kid"] != eab.kid.as_str() {
+            return Err(unauthorized("Unknown external account"));
+        }
+        if protected["url"] != jws.protected["url"] {
+            return Err(unauthorized("External account binding has the wrong url"));
+        }
+        if decode_json(&binding["payload"])? != jws.jwk {
+            return Err(unauthorized("External account binding has the wrong key"));
+        }
+
+        let data = format!(
+            "{}.{}",
+            binding["protected"].as_str().unwrap_or_default(),
+            binding["payload"].as_str().unwrap_or_default()
+        );
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &eab.hmac_key);
+        hmac::verify(&key, data.as_bytes(), &decode(&binding["signature"])?)
+            .map_err(|_| unauthorized("External account binding is invalid"))
+    }
+
+    fn new_account(&self, jws: Jws) -> Result<Reply, Problem> {
+        if jws.account.is_some() {
+            return Err(malformed("New accounts have to be signed with a jwk"));
+        }
+        let mut state = self.state.lock();
+        let account = json!({ "status": "valid", "contact": jws.payload["contact"] });
+
+        if let Some(index) = state.accounts.iter().position(|jwk| *jwk == jws.jwk) {
+            return Ok(reply(
+                StatusCode::OK,
+                Some(self.url("account", index)),
+                account,
+            ));
+        }
+        if jws.payload["onlyReturnExisting"] == true {
+            return Err(problem(
+                StatusCode::BAD_REQUEST,
+                "accountDoesNotExist",
+                "No account exists for the key",
+            ));
+        }
+
+        if let Some(eab) = &state.eab {
+            let binding = &jws.payload["externalAccountBinding"];
+            if binding.is_null() {
+                return Err(problem(
+                    StatusCode::UNAUTHORIZED,
+                    "externalAccountRequired",
+                    "Account needs an external account binding",
+                ));
+            }
+            self.verify_binding(eab, binding, &jws)?;
+        }
+
+        state.accounts.push(jws.jwk);
+        let url = self.url("account", state.accounts.len() - 1);
+        Ok(reply(StatusCode::CREATED, Some(url), account))
+    }
+
+    // RFC 8555 section 7.3.5
+    fn key_change(&self, account: usize, jws: &Jws) -> Result<Reply, Problem> {
+        let inner = &jws.payload;
+        let protected = decode_json(&inner["protected"])?;
+        if protected["alg"] != "ES256" || !protected["nonce"].is_null() {
+            return Err(malformed("Inner jws is invalid"));
+        }
+        if protected["url"] != jws.protected["url"] {
+            return Err(unauthorized("Inner jws has the wrong url"));
+        }
+        let jwk = protected["jwk"].clone();
+        let data = format!(
+            "{}.{}",
+            inner["protected"].as_str().unwrap_or_default(),
+            inner["payload"].as_str().unwrap_or_default()
+        );
+        if !jwk.is_object() || !verify_es256(&jwk, data.as_bytes(), &decode(&inner["signature"])?) {
+            return Err(malformed("Inner signature is invalid"));
+        }
+
+        let payload = decode_json(&inner["payload"])?;
+        let mut state = self.state.lock();
+        if payload["account"] != self.url("account", account).as_str()
+            || payload["oldKey"] != state.accounts[account]
+        {
+            return Err(unauthorized("Key change does not match the account"));
+        }
+        if state.accounts.contains(&jwk) {
+            return Err(problem(
+                StatusCode::CONFLICT,
+                "malformed",
+                "New key is already in use",
+            ));
+        }
+
+        state.accounts[account] = jwk;
+        Ok(reply(StatusCode::OK, None, json!({ "status": "valid" })))
+    }
+
+    fn order_json(&self, index: usize, order: &Order) -> Value {
+        let identifiers = order
+            .names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect::<Vec<_>>();
+        let authorizations = order
+            .authorizations
+            .iter()
+            .map(|authorization| self.url("authz", *authorization))
+            .collect::<Vec<_>>();
+
+        json!({
+            "status": order.status,
+            "identifiers": identifiers,
+            "authorizations": authorizations,
+            "finalize": self.url("finalize", index),
+            "certificate": order.certificate.as_ref().map(|_| self.url("cert", index)),
+        })
+    }
+
+    fn new_order(&self, account: usize, payload: &Value) -> Result<Reply, Problem> {
+        let names = payload["identifiers"]
+            .as_array()
+            .filter(|identifiers| !identifiers.is_empty())
+            .ok_or_else(|| malformed("Order needs identifiers"))?
+            .iter()
+            .map(|identifier| match identifier["type"].as_str() {
+                Some("dns") => identifier["value"]
+                    .as_str()
+                    .map(str::to_owned)
+                    .ok_or_else(|| malformed("Identifier has no value")),
+                _ => Err(problem(
+                    StatusCode::BAD_REQUEST,
+                    "unsupportedIdentifier",
+                    "Only dns identifiers are supported",
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut state = self.state.lock();
+        let mut authorizations = Vec::with_capacity(names.len());
+        for name in &names {
+            let mut token = [0; 16];
+            rand_bytes(&mut token).unwrap();
+            let (name, wildcard) = match name.strip_prefix("*.") {
+                Some(name) => (name, true),
+                None => (name.as_str(), false),
+            };
+            state.authorizations.push(Authorization {
+                account,
+                name: name.to_owned(),
+                wildcard,
+                token: b64(&token),
+                status: "pending",
+            });
+            authorizations.push(state.authorizations.len() - 1);
+        }
+
+        let order = Order {
+            account,
+            names,
+            authorizations,
+            status: "pending",
+            certificate: None,
+        };
+        let index = state.orders.len();
+        let body = self.order_json(index, &order);
+        state.orders.push(order);
+
+        Ok(reply(
+            StatusCode::CREATED,
+            Some(self.url("order", index)),
+            body,
+        ))
+    }
+
+    fn order(&self, account: usize, index: usize) -> Result<Reply, Problem> {
+        let mut state = self.state.lock();
+        state.order_polls += 1;
+        let order = state
+            .orders
+            .get(index)
+            .filter(|order| order.account == account)
+            .ok_or_else(not_found)?;
+
+        let mut reply = reply(StatusCode::OK, None, self.order_json(index, order));
+        if order.status == "pending" || order.status == "processing" {
+            reply.retry_after = state.retry_after;
+        }
+        Ok(reply)
+    }
+
+    fn challenge_json(&self, index: usize, authorization: &Authorization) -> Value {
+        json!({
+            "type": "dns-01",
+            "url": self.url("chall", index),
+            "token": authorization.token,
+            "status": authorization.status,
+        })
+    }
+
+    fn authorization(&self, account: usize, index: usize) -> Result<Reply, Problem> {
+        let state = self.state.lock();
+        let authorization = state
+            .authorizations
+            .get(index)
+            .filter(|authorization| authorization.account == account)
+            .ok_or_else(not_found)?;
+
+        let body = json!({
+            "status": authorization.status,
+            "identifier": { "type": "dns", "value": authorization.name },
+            "wildcard": authorization.wildcard,
+            "challenges": [self.challenge_json(index, authorization)],
+        });
+        Ok(reply(StatusCode::OK, None, body))
+    }
+
+    // every authorization has a single dns-01 challenge with the same index
+    async fn challenge(&self, account: usize, index: usize, jwk: &Value) -> Result<Reply, Problem> {
+        let (dns, name, expected) = {
+            let state = self.state.lock();
+            let authorization = state
+                .authorizations
+                .get(index)
+                .filter(|authorization| authorization.account == account)
+                .ok_or_else(not_found)?;
+            let name = format!("_acme-challenge.{}.", authorization.name);
+            let expected = key_authorization(&authorization.token, jwk);
+            (state.dns, name, expected)
+        };
+
+        let valid = match dns {
+            Some(dns) => match lookup_txt(dns, &name).await {
+                Ok(txt) => txt.contains(&expected),
+                Err(_) => false,
+            },
+            None => false,
+        };
+
+        let mut state = self.state.lock();
+        let status = if valid { "valid" } else { "invalid" };
+        state.authorizations[index].status = status;
+        // a single failed challenge fails the whole order
+        for order in state.orders.iter_mut() {
+            if !valid && order.status == "pending" && order.authorizations.contains(&index) {
+                order.status = "invalid";
+            }
+        }
+        let ready = state
+            .orders
+            .iter()
+            .enumerate()
+            .filter(|(_, order)| order.status == "pending")
+            .filter(|(_, order)| {
+                order
+                    .authorizations
+                    .iter()
+                    .all(|authorization| state.authorizations[*authorization].status == "valid")
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        for order in ready {
+            state.orders[order].status = "ready";
+        }
+
+        let body = self.challenge_json(index, &state.authorizations[index]);
+        Ok(reply(StatusCode::OK, None, body))
+    }
+
+    fn finalize(&self, account: usize, index: usize, payload: &Value) -> Result<Reply, Problem> {
+        let mut state = self.state.lock();
+        let order = state
+            .orders
+            .get_mut(index)
+            .filter(|order| order.account == account)
+            .ok_or_else(not_found)?;
+        if order.status != "ready" {
+            return Err(problem(
+                StatusCode::FORBIDDEN,
+                "orderNotReady",
+                format!("Order is {}", order.status),
+            ));
+        }
+
+        let csr = X509Req::from_der(&decode(&payload["csr"])?)
+            .map_err(|_| problem(StatusCode::BAD_REQUEST, "badCSR", "Csr is invalid"))?;
+        let pem = issue(&csr, &order.names)
+            .map_err(|e| problem(StatusCode::BAD_REQUEST, "badCSR", e.to_string()))?;
+        order.status = "valid";
+        order.certificate = Some(pem);
+
+        let body = self.order_json(index, order);
+        Ok(reply(StatusCode::OK, None, body))
+    }
+
+    fn certificate(&self, account: usize, index: usize) -> Result<Reply, Problem> {
+        let state = self.state.lock();
+        let pem = state
+            .orders
+            .get(index)
+            .filter(|order| order.account == account)
+            .and_then(|order| order.certificate.clone())
+            .ok_or_else(not_found)?;
+
+        Ok(Reply {
+            status: StatusCode::OK,
+            location: None,
+            retry_after: None,
+            content_type: "application/pem-certificate-chain",
+            body: pem,
+        })
+    }
+}
@@ -112,7 +112,7 @@ pub(crate) fn metrics_wrapper<F>(
 where
     F: Filter<Extract = (WarpResponse, MetricsConfig), Error = Rejection> + Clone + Send + 'static,
 {
-    metrics_wrapper_higher(&*HTTP_REQ_HISTOGRAM, &*HTTP_STATUS_COUNTER)(filter)
+    metrics_wrapper_higher(&HTTP_REQ_HISTOGRAM, &HTTP_STATUS_COUNTER)(filter)
 }
 
 fn internal_server_error_and_trace<E: Display>(error: &E) -> WarpResponse {
@@ -22,6 +22,8 @@ mod proxy;
 mod routes;
 pub mod tls;
 
+use tls::TlsConfig;
+
 lazy_static! {
     static ref TCP_TOTAL_CONNECTION_COUNTER: IntCounterVec = register_int_counter_vec!(
         "tcp_total_connection_counter",
@@ -86,6 +88,7 @@ pub(crate) async fn new<F>(
     (https, https_proxy): Listener,
     (prom, prom_proxy): Listener,
     facade: F,
+    tls: Arc<TlsConfig>,
 ) -> Result<()>
 where
     F: DomainFacade + CertFacade + Clone + Send + Sync + 'static,
@@ -110,7 +113,7 @@ where
 
     let https = https
         .map(move |https| proxy::wrap(https, https_proxy))
-        .map(|https| tls::wrap(https, facade))
+        .map(|https| tls::wrap(https, tls))
         .map(|https| serve(https, routes, "HTTPS").instrument(info_span!("HTTPS")))
         .map(tokio::spawn);
 
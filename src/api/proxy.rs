@@ -172,10 +172,10 @@ fn format_header(res: Header) -> IoResult<SocketAddr> {
             SocketAddrV6::new(source_address.into(), port, 0, 0).into()
         }
         address => {
-            return Err(IoError::new(
-                ErrorKind::Other,
-                format!("Cannot convert {:?} to a SocketAddr", address),
-            ))
+            return Err(IoError::other(format!(
+                "Cannot convert {:?} to a SocketAddr",
+                address
+            )))
         }
     };
 
@@ -241,7 +241,7 @@ where
 mod tests {
     use futures_util::future;
     use ppp::model::{Addresses, Command, Header, Protocol, Version};
-    use std::io::{Error as IoError, ErrorKind, IoSlice, Result as IoResult};
+    use std::io::{Error as IoError, IoSlice, Result as IoResult};
     use std::net::SocketAddr;
     use std::pin::Pin;
     use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -320,7 +320,7 @@ mod tests {
             let header = ppp::to_bytes(generate_ipv4()).unwrap();
             let mut builder = Builder::new();
             builder.read(&header[..10]);
-            builder.read_error(IoError::new(ErrorKind::Other, "Error on IO"));
+            builder.read_error(IoError::other("Error on IO"));
             builder.build().source(ProxyProtocol::Enabled)
         };
 
@@ -344,14 +344,14 @@ mod tests {
         assert!(format_header(generate_header(addresses)).is_err());
     }
 
-    #[test]
-    fn test_remote_addr_delegation() {
-        impl RemoteAddr for &[u8] {
-            fn remote_addr(&self) -> IoResult<SocketAddr> {
-                Ok(SocketAddr::from(([1, 1, 1, 1], 443)))
-            }
+    impl RemoteAddr for &[u8] {
+        fn remote_addr(&self) -> IoResult<SocketAddr> {
+            Ok(SocketAddr::from(([1, 1, 1, 1], 443)))
         }
+    }
 
+    #[test]
+    fn test_remote_addr_delegation() {
         let proxy_stream = &mut &[].source(ProxyProtocol::Enabled);
         let actual = proxy_stream.remote_addr().unwrap();
         assert_eq!(SocketAddr::from(([1, 1, 1, 1], 443)), actual)
@@ -364,7 +364,7 @@ mod tests {
         builder.write("Test2".as_ref());
 
         let mut proxy_stream = builder.build().source(ProxyProtocol::Disabled);
-        assert_eq!(false, proxy_stream.is_write_vectored());
+        assert!(!proxy_stream.is_write_vectored());
 
         proxy_stream.write_all("Test1".as_ref()).await.unwrap();
 
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use serde::Serialize;
 use std::convert::TryFrom;
 use tracing::error;
 use warp::filters::trace;
@@ -7,14 +8,25 @@ use warp::reply::Response as WarpResponse;
 use warp::{Filter, Rejection, Reply};
 
 use super::{metrics_wrapper, MetricsConfig};
-use crate::facade::{Domain, DomainDTO, DomainFacade};
+use crate::facade::{Domain, DomainDTO, DomainFacade, DomainKey, TsigDTO};
+
+#[derive(Serialize)]
+struct RegisterDTO {
+    #[serde(flatten)]
+    domain: DomainDTO,
+    tsig: TsigDTO,
+}
 
 async fn register_handler<F: DomainFacade>(facade: F) -> Result<WarpResponse, Rejection> {
-    let res: Result<DomainDTO> = async {
+    let res: Result<RegisterDTO> = async {
         let res = DomainDTO::default();
         let domain = Domain::try_from(res.clone())?;
-        facade.create_domain(&domain).await?;
-        Ok(res)
+        let key = DomainKey::new(&domain.id)?;
+        facade.create_domain_with_key(&domain, &key).await?;
+        Ok(RegisterDTO {
+            domain: res,
+            tsig: key.into(),
+        })
     }
     .await;
 
@@ -1,21 +1,116 @@
-use acme_lib::order::NewOrder;
-use acme_lib::{create_p384_key, Directory, DirectoryUrl};
 use anyhow::{anyhow, Result};
-use std::sync::Arc;
+use lazy_static::lazy_static;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use std::convert::TryFrom;
 use std::time::Duration;
-use tokio::runtime::Runtime;
 use tokio::time::Interval;
-use tracing::{error, info, Instrument, Span};
+use tracing::{error, info, Instrument};
 
-use crate::acme::DatabasePersist;
-use crate::facade::{Cert, CertFacade, Domain, DomainFacade};
-use crate::util::HOUR_IN_SECONDS;
+use crate::acme::client::{Account, Directory, Order, Status};
+use crate::acme::{account_realm, AccountKey, DatabasePersist};
+use crate::config::Eab;
+use crate::facade::{AcmeFacade, Cert, CertFacade, Domain, DomainFacade, Keyring};
+use crate::util::{now, to_i64, HOUR_IN_SECONDS};
+
+lazy_static! {
+    static ref CERT_RENEWAL_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "cert_renewal_counter",
+        "Sum of certificate renewal checks",
+        &["result"]
+    )
+    .unwrap();
+    static ref CERT_NEXT_RENEWAL_GAUGE: IntGauge = register_int_gauge!(
+        "cert_next_renewal_timestamp_seconds",
+        "Unix time at which the stored certificate gets renewed"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Renewal {
+    Renewed,
+    NotDue,
+    // another replica holds the cert
+    InProgress,
+}
+
+impl Renewal {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Renewal::Renewed => "renewed",
+            Renewal::NotDue => "not_due",
+            Renewal::InProgress => "in_progress",
+        }
+    }
+}
+
+pub(crate) fn unix_time(time: &Asn1TimeRef) -> Result<u64> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    let secs = i64::from(diff.days) * 24 * HOUR_IN_SECONDS as i64 + i64::from(diff.secs);
+
+    u64::try_from(secs).map_err(|_| anyhow!("Time {} is before 1970", time))
+}
+
+// unix time of the notAfter field of a pem encoded certificate
+pub(crate) fn not_after(pem: &str) -> Result<u64> {
+    let cert = X509::from_pem(pem.as_bytes())?;
+    unix_time(cert.not_after())
+}
+
+// certs without a parsable pem get renewed right away
+fn renewal_time(cert: &Cert, renew_before: Duration) -> Option<u64> {
+    let pem = cert.cert.as_ref()?;
+    match not_after(pem) {
+        Ok(not_after) => Some(not_after.saturating_sub(renew_before.as_secs())),
+        Err(e) => {
+            error!("Could not parse stored cert {}", e);
+            None
+        }
+    }
+}
+
+// the key of the certificate, PKCS#8 encoded
+fn create_private_key() -> Result<PKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+// DER encoded request for all names, the first one is the common name
+fn create_csr(names: &[String], private: &PKey<Private>) -> Result<Vec<u8>> {
+    let mut builder = X509ReqBuilder::new()?;
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_nid(Nid::COMMONNAME, &names[0])?;
+    builder.set_subject_name(&subject.build())?;
+    builder.set_pubkey(private)?;
+
+    let mut san = SubjectAlternativeName::new();
+    for name in names {
+        san.dns(name);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+
+    builder.sign(private, MessageDigest::sha256())?;
+    Ok(builder.build().to_der()?)
+}
 
-#[derive(Clone)]
 pub struct CertManager<F> {
     facade: F,
-    directory: Directory<DatabasePersist>,
-    runtime: Arc<Runtime>,
+    persist: DatabasePersist<F>,
+    directory: Directory,
+    contact: Vec<String>,
+    eab: Option<Eab>,
+    names: Vec<String>,
+    renew_before: Duration,
 }
 
 fn interval() -> Interval {
@@ -24,27 +119,26 @@ fn interval() -> Interval {
 
 impl<F> CertManager<F>
 where
-    F: DomainFacade + CertFacade + Clone + Send + Sync + 'static,
+    F: DomainFacade + CertFacade + AcmeFacade + Clone + Send + Sync + 'static,
 {
-    #[tracing::instrument(name = "CertManager::new", skip(facade, persist, runtime))]
-    pub async fn new(
+    pub fn new(
         facade: F,
-        persist: DatabasePersist,
-        acme: String,
-        runtime: &Arc<Runtime>,
-    ) -> Result<Self> {
-        let span = Span::current();
-        let directory = tokio::task::spawn_blocking(move || {
-            let _enter = span.enter();
-            Directory::from_url(persist, DirectoryUrl::Other(&acme))
-        })
-        .await??;
-
-        Ok(CertManager {
+        keyring: Option<Keyring>,
+        directory: Directory,
+        contact: Vec<String>,
+        eab: Option<Eab>,
+        names: Vec<String>,
+        renew_before: Duration,
+    ) -> Self {
+        CertManager {
+            persist: DatabasePersist::new(facade.clone(), keyring),
             facade,
             directory,
-            runtime: Arc::clone(runtime),
-        })
+            contact,
+            eab,
+            names,
+            renew_before,
+        }
     }
 
     // maybe useless function
@@ -57,15 +151,18 @@ where
                 loop {
                     interval.tick().await;
                     info!("Started Interval");
-                    if true {
-                        info!("Skipping Interval");
-                        continue;
-                    }
-                    if let Err(e) = self.manage().await {
-                        error!("{}", e);
-                        continue;
+                    match self.manage().await {
+                        Ok(renewal) => {
+                            CERT_RENEWAL_COUNTER
+                                .with_label_values(&[renewal.as_str()])
+                                .inc();
+                            info!(result = renewal.as_str(), "Interval successfully passed");
+                        }
+                        Err(e) => {
+                            CERT_RENEWAL_COUNTER.with_label_values(&["failed"]).inc();
+                            error!("{}", e);
+                        }
                     }
-                    info!("Interval successfully passed");
                 }
             }
             .in_current_span(),
@@ -75,73 +172,311 @@ where
         Ok(())
     }
 
-    async fn manage(&self) -> Result<()> {
+    fn renewal_time(&self, cert: &Cert) -> Option<u64> {
+        let renewal = renewal_time(cert, self.renew_before)?;
+        CERT_NEXT_RENEWAL_GAUGE.set(to_i64(&renewal));
+        Some(renewal)
+    }
+
+    async fn account_key(&self) -> Result<AccountKey> {
+        let realm = account_realm(&self.contact);
+        if let Some(account_key) = self.persist.account_key(realm).await? {
+            return Ok(account_key);
+        }
+
+        info!("Creating acme account key");
+        let account_key = AccountKey::generate()?;
+        self.persist.put_account_key(realm, &account_key).await?;
+        Ok(account_key)
+    }
+
+    async fn manage(&self) -> Result<Renewal> {
+        let renewal = match self.facade.first_cert().await? {
+            Some(cert) => self.renewal_time(&cert),
+            None => None,
+        };
+        if let Some(renewal) = renewal {
+            if renewal > now() {
+                return Ok(Renewal::NotDue);
+            }
+        }
+
         // maybe context is not needed here
         let memory_cert = match self.facade.start_cert().await? {
             Some(memory_cert) => memory_cert,
-            None => return Ok(()),
+            None => return Ok(Renewal::InProgress),
         };
 
+        let mut cert = match self.renew(memory_cert.clone()).await {
+            Ok(cert) => cert,
+            Err(e) => {
+                // the next interval can retry right away instead of waiting for the job to time out
+                if let Err(abort_error) = self.facade.abort_cert(&memory_cert).await {
+                    error!("Could not release cert lock {}", abort_error);
+                }
+                return Err(e);
+            }
+        };
+
+        self.facade.stop_cert(&mut cert).await?;
+        self.renewal_time(&cert);
+        info!(names = ?self.names, "Renewed cert");
+
+        Ok(Renewal::Renewed)
+    }
+
+    async fn renew(&self, memory_cert: Cert) -> Result<Cert> {
         let domain = self
             .facade
             .find_domain_by_id(&memory_cert.domain)
             .await?
             .ok_or_else(|| anyhow!("Could not find domain: {}", &memory_cert.domain))?;
 
-        let directory = self.directory.clone();
-        let facade = self.facade.clone();
-        let runtime = Arc::clone(&self.runtime);
-
-        let span = Span::current();
-        let mut cert = tokio::task::spawn_blocking(move || {
-            let _span = span.enter();
-            let account = directory.account("acme-dns-rust@byom.de")?;
-            let order = account.new_order("acme.conblem.me", &[])?;
-            CertManager::validate(memory_cert, domain, order, facade, &runtime)
-        })
-        .await??;
-
-        self.facade.stop_cert(&mut cert).await?;
-
-        Ok(())
+        let key = self.account_key().await?;
+        let account = self
+            .directory
+            .account(key, self.contact.clone(), self.eab.as_ref())
+            .await?;
+        let order = account.new_order(&self.names).await?;
+        self.validate(&account, order, memory_cert, domain).await
     }
 
-    fn validate(
+    async fn validate(
+        &self,
+        account: &Account<'_>,
+        mut order: Order,
         mut memory_cert: Cert,
         mut domain: Domain,
-        mut order: NewOrder<DatabasePersist>,
-        facade: F,
-        runtime: &Runtime,
     ) -> Result<Cert> {
-        let ord_csr = loop {
-            if let Some(ord_csr) = order.confirm_validations() {
-                break ord_csr;
+        if order.status == Status::Pending {
+            let mut challenges = Vec::new();
+            for url in &order.authorizations {
+                let authorization = account.authorization(url).await?;
+                // authorizations of previous orders can still be valid
+                if authorization.status != Status::Pending {
+                    continue;
+                }
+                let name = authorization.identifier.value;
+                let challenge = authorization
+                    .challenges
+                    .into_iter()
+                    .find(|challenge| challenge.kind == "dns-01")
+                    .ok_or_else(|| anyhow!("Authorization of {} has no dns challenge", name))?;
+                challenges.push((url, challenge));
             }
 
-            let chall = order
-                .authorizations()?
-                .get(0)
-                .ok_or_else(|| anyhow!("couldn't unpack auths"))?
-                .dns_challenge();
+            // apex and wildcard share the challenge name so all proofs are served at once
+            if !challenges.is_empty() {
+                domain.txt = challenges
+                    .iter()
+                    .map(|(_, challenge)| account.dns_proof(challenge))
+                    .collect::<Result<Vec<_>>>()?;
+                self.facade.update_domain(&domain).await?;
+            }
 
-            domain.txt = Some(chall.dns_proof());
-            let update = facade.update_domain(&domain);
-            runtime.block_on(update.in_current_span())?;
+            for (_, challenge) in &challenges {
+                account.validate(challenge).await?;
+            }
+            for (url, _) in challenges {
+                let authorization = account.wait_authorization(url).await?;
+                if authorization.status != Status::Valid {
+                    return Err(anyhow!(
+                        "Authorization of {} is {:?}",
+                        authorization.identifier.value,
+                        authorization.status
+                    ));
+                }
+            }
+            order = account.wait_order(&order.url).await?;
+        }
+        if order.status != Status::Ready {
+            return Err(anyhow!("Order {} is {:?}", order.url, order.status));
+        }
 
-            chall.validate(5000)?;
-            order.refresh()?;
+        let private = create_private_key()?;
+        let order = account
+            .finalize(&order, &create_csr(&self.names, &private)?)
+            .await?;
+        let order = match order.status {
+            Status::Valid => order,
+            _ => account.wait_order(&order.url).await?,
         };
+        let certificate = match (order.status, &order.certificate) {
+            (Status::Valid, Some(certificate)) => certificate,
+            _ => return Err(anyhow!("Order {} is {:?}", order.url, order.status)),
+        };
+        let cert = account.certificate(certificate).await?;
 
-        let private = create_p384_key();
-        let ord_crt = ord_csr.finalize_pkey(private, 5000)?;
-        let cert = ord_crt.download_and_save_cert()?;
-
-        let private = cert.private_key().to_string();
-        let cert = cert.certificate().to_string();
-
-        memory_cert.private = Some(private);
+        memory_cert.private = Some(String::from_utf8(private.private_key_to_pem_pkcs8()?)?);
         memory_cert.cert = Some(cert);
+        // the response of the old cert would fail the handshake
+        memory_cert.ocsp = None;
 
         Ok(memory_cert)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use openssl::nid::Nid;
+    use openssl::x509::{X509Req, X509};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::Arc;
+
+    use super::{create_csr, create_private_key, not_after, renewal_time, CertManager, Renewal};
+    use crate::acme::client::Directory;
+    use crate::acme::pebble::Pebble;
+    use crate::api::tls::tests::presented;
+    use crate::api::tls::{DatabaseCerts, TlsConfig};
+    use crate::config::{PreconfiguredRecords, TsigKeys};
+    use crate::dns::{DatabaseAuthority, Dns};
+    use crate::facade::cert::tests::create_cert;
+    use crate::facade::{CertFacade, InMemoryFacade, State};
+
+    const NAME: &str = "acme.example.com";
+    const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+    // notAfter of tests/ca.crt is Jul 6 18:36:05 2048 GMT
+    const NOT_AFTER: u64 = 2477673365;
+
+    #[test]
+    fn parse_not_after() {
+        let cert = create_cert();
+        assert_eq!(NOT_AFTER, not_after(cert.cert.as_ref().unwrap()).unwrap());
+        assert!(not_after("WRONG").is_err());
+    }
+
+    #[test]
+    fn csr_has_all_names() {
+        let names = vec![
+            "acme.example.com".to_owned(),
+            "*.acme.example.com".to_owned(),
+        ];
+        let private = create_private_key().unwrap();
+        let der = create_csr(&names, &private).unwrap();
+        let csr = X509Req::from_der(&der).unwrap();
+        assert!(csr.verify(&private).unwrap());
+
+        let common_name = csr.subject_name().entries_by_nid(Nid::COMMONNAME).next();
+        let common_name = common_name.unwrap().data().as_slice();
+        assert_eq!(b"acme.example.com", common_name);
+        // the san extension holds every name
+        for name in &names {
+            assert!(der.windows(name.len()).any(|part| part == name.as_bytes()));
+        }
+    }
+
+    // serves the acme challenges of the facade on a free port of localhost
+    async fn dns_listener(facade: &InMemoryFacade) -> SocketAddr {
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let records = PreconfiguredRecords::default();
+        let authority = DatabaseAuthority::new(facade.clone(), NAME, records.clone(), false, None);
+        let dns = Dns::new(
+            addr,
+            authority,
+            Vec::new(),
+            &records,
+            facade.clone(),
+            TsigKeys::default(),
+            None,
+            None,
+            None,
+            None,
+        );
+        tokio::spawn(dns.spawn());
+
+        // the port is taken once the listener is up
+        while UdpSocket::bind(addr).is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        addr
+    }
+
+    async fn cert_manager(facade: &InMemoryFacade, pebble: &Pebble) -> CertManager<InMemoryFacade> {
+        let directory = Directory::from_url(&pebble.directory(), None)
+            .await
+            .unwrap();
+        let names = vec![NAME.to_owned(), format!("*.{}", NAME)];
+        CertManager::new(
+            facade.clone(),
+            None,
+            directory,
+            Vec::new(),
+            None,
+            names,
+            RENEW_BEFORE,
+        )
+    }
+
+    #[tokio::test]
+    async fn issues_cert_with_dns_challenge() {
+        let facade = InMemoryFacade::default();
+        let config = TlsConfig::new();
+        let empty = config.load();
+        tokio::spawn(DatabaseCerts::new(facade.clone(), Arc::clone(&config)).watch());
+        let pebble = Pebble::start(None, Some(dns_listener(&facade).await));
+        let cert_manager = cert_manager(&facade, &pebble).await;
+
+        assert_eq!(Renewal::Renewed, cert_manager.manage().await.unwrap());
+        let cert = facade.first_cert().await.unwrap().unwrap();
+        assert_eq!(State::Ok, cert.state);
+        let leaf = X509::from_pem(cert.cert.as_ref().unwrap().as_bytes()).unwrap();
+        let names = leaf
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|name| name.dnsname().map(str::to_owned))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![NAME.to_owned(), format!("*.{}", NAME)], names);
+
+        // the stored cert gets pushed to the https api
+        for _ in 0..100 {
+            if !Arc::ptr_eq(&empty, &config.load()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let served = presented(&config, Some("api.acme.example.com")).await;
+        assert_eq!(leaf.to_der().unwrap(), served);
+
+        // a fresh cert is not renewed
+        assert_eq!(Renewal::NotDue, cert_manager.manage().await.unwrap());
+        assert_eq!(1, pebble.orders());
+    }
+
+    #[tokio::test]
+    async fn failed_challenge_stores_no_cert() {
+        let facade = InMemoryFacade::default();
+        // nothing answers the challenge lookups
+        let pebble = Pebble::start(None, None);
+        let cert_manager = cert_manager(&facade, &pebble).await;
+
+        let error = cert_manager.manage().await.unwrap_err();
+        assert!(error.to_string().contains("Invalid"), "{}", error);
+        let cert = facade.first_cert().await.unwrap().unwrap();
+        assert_eq!(None, cert.cert);
+        // the lock got released so another try can start right away
+        assert_eq!(State::Ok, cert.state);
+        assert!(facade.start_cert().await.unwrap().is_some());
+    }
+
+    #[test]
+    fn renewal_time_works() {
+        let mut cert = create_cert();
+        let renew_before = Duration::from_secs(30 * 24 * 3600);
+        assert_eq!(
+            Some(NOT_AFTER - 30 * 24 * 3600),
+            renewal_time(&cert, renew_before)
+        );
+
+        cert.cert = Some("WRONG".to_owned());
+        assert_eq!(None, renewal_time(&cert, renew_before));
+        cert.cert = None;
+        assert_eq!(None, renewal_time(&cert, renew_before));
+    }
+}
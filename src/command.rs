@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use std::fs::read;
+use std::io::{stdout, Write};
+use tracing::info;
+
+use crate::acme::account::{export, import, rollover};
+use crate::acme::client::Directory;
+use crate::acme::{account_realm, DatabasePersist};
+use crate::config::Config;
+use crate::facade::{AcmeFacade, CertFacade, DomainFacade, DomainKey, Keyring, TsigDTO};
+
+const USAGE: &str = "Usage: acme-dns-rust <config> account export | account import <file> [--force] | account rollover | domain key <id> | encryption rotate";
+
+// commands work on the stored state and exit instead of starting the server
+pub(crate) async fn run<F>(
+    facade: F,
+    keyring: Option<Keyring>,
+    config: &Config,
+    args: &[String],
+) -> Result<()>
+where
+    F: AcmeFacade + CertFacade + DomainFacade + Clone,
+{
+    let persist = DatabasePersist::new(facade.clone(), keyring);
+    let contact = config.acme.contact()?;
+    let realm = account_realm(&contact);
+
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["account", "export"] => {
+            let pem = export(&persist, realm).await?;
+            stdout().write_all(&pem)?;
+        }
+        ["account", "import", file, force @ ..] if force.is_empty() || force == ["--force"] => {
+            let pem = read(file)?;
+            import(&persist, realm, &pem, !force.is_empty()).await?;
+            info!(realm, "Imported acme account key");
+        }
+        ["account", "rollover"] => {
+            let ca = config.acme.read_ca()?;
+            let directory = Directory::from_url(&config.general.acme, ca.as_deref()).await?;
+            let account = rollover(&persist, &directory, realm).await?;
+            info!(realm, %account, "Rolled over acme account key");
+        }
+        // registrations from before dynamic updates have no key, a new key replaces the old one
+        ["domain", "key", id] => {
+            if facade.find_domain_by_id(id).await?.is_none() {
+                return Err(anyhow!("Domain {} does not exist", id));
+            }
+            let key = DomainKey::new(id)?;
+            facade.put_domain_key(&key).await?;
+            serde_json::to_writer(stdout(), &TsigDTO::from(key))?;
+            writeln!(stdout())?;
+            info!(domain = id, "Issued TSIG key");
+        }
+        // encrypts every private key and tsig secret with the current key, previous keys can be removed afterwards
+        ["encryption", "rotate"] => {
+            let certs = facade.rotate_certs().await?;
+            let keys = facade.rotate_domain_keys().await?;
+            let acme = persist.rotate().await?;
+            info!(certs, keys, acme, "Rotated encryption key");
+        }
+        _ => return Err(anyhow!(USAGE)),
+    }
+
+    Ok(())
+}
@@ -2,9 +2,10 @@ use serde::de::{SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
 use std::fmt::Formatter;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
 pub enum ProxyProtocol {
     Enabled,
+    #[default]
     Disabled,
 }
 
@@ -20,12 +21,6 @@ impl<'de> Deserialize<'de> for ProxyProtocol {
     }
 }
 
-impl Default for ProxyProtocol {
-    fn default() -> Self {
-        Self::Disabled
-    }
-}
-
 pub type Listener = (Option<String>, ProxyProtocol);
 
 pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Listener, D::Error>
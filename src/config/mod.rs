@@ -1,15 +1,32 @@
-use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::fs::read;
+use anyhow::{anyhow, Context, Result};
+use hyper::Uri;
+use ipnet::IpNet;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Formatter;
+use std::fs::{read, read_to_string};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use tracing::{debug, info, info_span, trace};
+use trust_dns_server::proto::rr::rdata::caa::KeyValue;
+use trust_dns_server::proto::rr::rdata::CAA;
+use trust_dns_server::proto::rr::Name;
+
+use crate::facade::Keyring;
 
 pub use listener::{Listener, ProxyProtocol};
 pub use records::PreconfiguredRecords;
 use trust_dns_server::resolver::config::ResolverConfig;
+pub use tsig::{TsigAlgorithm, TsigKey, TsigKeys};
 
 mod dns;
 mod listener;
 mod records;
+mod tsig;
 
 #[derive(Deserialize, Debug)]
 pub struct Api {
@@ -28,6 +45,7 @@ fn default_acme() -> String {
 
 #[derive(Deserialize, Debug)]
 pub struct General {
+    #[allow(dead_code)]
     #[serde(default, deserialize_with = "dns::deserialize")]
     pub test: Option<ResolverConfig>,
     pub dns: String,
@@ -35,14 +53,515 @@ pub struct General {
     #[serde(default = "default_acme")]
     pub acme: String,
     pub name: String,
+    // the cert gets renewed once fewer days are left
+    #[serde(default = "default_renew_days")]
+    pub renew_days: u64,
+}
+
+// zone transfers are only enabled if this section is present
+// a secondary has to match one of the networks in allow (if any are configured)
+// and has to sign its request with one of the keys (if any are configured)
+// secondaries in notify get told about changes so they do not have to wait for the refresh
+#[derive(Deserialize, Debug, Clone)]
+pub struct Transfer {
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    #[serde(default, deserialize_with = "tsig::deserialize_names")]
+    pub keys: Vec<Name>,
+    #[serde(default)]
+    pub notify: Vec<SocketAddr>,
+}
+
+// dynamic updates are only accepted if this section is present
+// a client has to match one of the networks in allow (if any are configured)
+// the tsig key of the registration is checked for every update as well
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Update {
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+fn default_responses_per_second() -> u32 {
+    5
+}
+
+fn default_slip() -> u32 {
+    2
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    56
+}
+
+// response rate limiting for udp, only enabled if this section is present
+// every slip-th limited response gets sent truncated instead of dropped, 0 drops all
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rrl {
+    #[serde(default = "default_responses_per_second")]
+    pub responses_per_second: u32,
+    #[serde(default = "default_slip")]
+    pub slip: u32,
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+const DEFAULT_RENEW_DAYS: u64 = 30;
+
+fn default_renew_days() -> u64 {
+    DEFAULT_RENEW_DAYS
+}
+
+const DEFAULT_POLL_TIMEOUT: u64 = 10;
+
+fn default_poll_timeout() -> u64 {
+    DEFAULT_POLL_TIMEOUT
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// dnstap logging of queries and responses, only enabled if this section is present
+// frames either get sent to the unix socket of a collector or written to a file
+#[derive(Deserialize, Debug, Clone)]
+pub struct Dnstap {
+    pub socket: Option<PathBuf>,
+    pub file: Option<PathBuf>,
+    pub identity: Option<String>,
+    #[serde(default = "default_true")]
+    pub udp: bool,
+    #[serde(default = "default_true")]
+    pub tcp: bool,
+}
+
+// issuer domains of well known acme directories
+// other directories need an explicit issuer, their host says nothing about it
+const CAA_ISSUERS: &[(&str, &str)] = &[
+    ("letsencrypt.org", "letsencrypt.org"),
+    ("pki.goog", "pki.goog"),
+    ("zerossl.com", "sectigo.com"),
+    ("buypass.com", "buypass.com"),
+    ("buypass.no", "buypass.com"),
+];
+
+fn caa_issuer(acme: &str) -> Result<String> {
+    let directory = Uri::from_str(acme)?;
+    let host = directory
+        .host()
+        .ok_or_else(|| anyhow!("Acme directory {} has no host", acme))?;
+
+    let issuer = CAA_ISSUERS
+        .iter()
+        .find(|(suffix, _)| host == *suffix || host.ends_with(&format!(".{}", suffix)));
+    match issuer {
+        Some((_, issuer)) => Ok(issuer.to_string()),
+        None => Err(anyhow!(
+            "Caa issuer of acme directory {} is unknown, set it with issuer",
+            acme
+        )),
+    }
+}
+
+// caa records for general.name, only synthesized if this section is present
+// account_uri pins issuance to our acme account as described in RFC 8657
+#[derive(Deserialize, Debug, Clone)]
+pub struct Caa {
+    pub issuer: Option<String>,
+    pub account_uri: Option<String>,
+}
+
+impl Caa {
+    pub fn rdata(&self, acme: &str) -> Result<CAA> {
+        let issuer = match &self.issuer {
+            Some(issuer) => issuer.clone(),
+            None => caa_issuer(acme)?,
+        };
+        let issuer = Name::from_str(&issuer)?;
+
+        let mut options = Vec::new();
+        if let Some(account_uri) = &self.account_uri {
+            let account = Uri::from_str(account_uri)?;
+            let directory = Uri::from_str(acme)?;
+            if account.scheme_str() != Some("https") || account.host() != directory.host() {
+                return Err(anyhow!(
+                    "Caa account uri {} does not belong to {}",
+                    account_uri,
+                    acme
+                ));
+            }
+            // would end the value in the record
+            if account_uri.contains(';') {
+                return Err(anyhow!("Caa account uri {} contains ;", account_uri));
+            }
+            options.push(KeyValue::new("accounturi", account_uri));
+        }
+
+        Ok(CAA::new_issue(false, Some(issuer), options))
+    }
+}
+
+const DEFAULT_TLS_INTERVAL: u64 = 60;
+
+fn default_tls_interval() -> u64 {
+    DEFAULT_TLS_INTERVAL
+}
+
+// certificate chain and key in pem files
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// if cert and key are set the https api uses them instead of the acme certificate
+// the sni certificates are only served to clients asking for one of their names
+// if this section is present, the files are checked for changes every interval seconds
+#[derive(Deserialize, Debug, Clone)]
+pub struct Tls {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub sni: Vec<TlsFiles>,
+    #[serde(default = "default_tls_interval")]
+    pub interval: u64,
+}
+
+impl Tls {
+    // the certificate which replaces the acme one
+    pub fn default_files(&self) -> Option<TlsFiles> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// external account binding as handed out by the ca, RFC 8555 section 7.3.4
+#[derive(Clone, PartialEq)]
+pub struct Eab {
+    pub kid: String,
+    pub hmac_key: Vec<u8>,
+}
+
+// never print the hmac key
+impl std::fmt::Debug for Eab {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Eab")
+            .field("kid", &self.kid)
+            .field("hmac_key", &"******")
+            .finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for Eab {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct RawEab<'a> {
+            kid: String,
+            hmac_key: &'a str,
+        }
+
+        // cas hand out the key base64url encoded, some with padding
+        let RawEab { kid, hmac_key } = RawEab::deserialize(deserializer)?;
+        let hmac_key =
+            base64::decode_config(hmac_key.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                .map_err(DeError::custom)?;
+
+        Ok(Eab { kid, hmac_key })
+    }
+}
+
+// account and identifiers of the certificate the api is served with
+// name defaults to general.name and always comes first in the order
+// wildcard adds *.name so the api is reachable under every registration subdomain
+// ca is a pem file with a root to trust for the acme directory besides the native ones
+// poll_timeout is how many minutes an order may stay pending or processing
+#[derive(Deserialize, Debug, Clone)]
+pub struct Acme {
+    #[serde(default)]
+    pub contact: Vec<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub sans: Vec<String>,
+    #[serde(default = "default_true")]
+    pub wildcard: bool,
+    pub eab: Option<Eab>,
+    pub ca: Option<PathBuf>,
+    #[serde(default = "default_poll_timeout")]
+    pub poll_timeout: u64,
+}
+
+impl Default for Acme {
+    fn default() -> Self {
+        Acme {
+            contact: Vec::new(),
+            name: None,
+            sans: Vec::new(),
+            wildcard: true,
+            eab: None,
+            ca: None,
+            poll_timeout: DEFAULT_POLL_TIMEOUT,
+        }
+    }
+}
+
+impl Acme {
+    // contacts as mailto uris as described in RFC 8555 section 7.3
+    pub fn contact(&self) -> Result<Vec<String>> {
+        self.contact
+            .iter()
+            .map(|email| {
+                let (local, domain) = email
+                    .split_once('@')
+                    .ok_or_else(|| anyhow!("Acme contact {} is not an email", email))?;
+                let invalid = |part: &str| {
+                    part.is_empty()
+                        || part.contains(|c: char| "@,?:".contains(c) || c.is_whitespace())
+                };
+                if invalid(local) || invalid(domain) {
+                    return Err(anyhow!("Acme contact {} is not an email", email));
+                }
+                Ok(format!("mailto:{}", email))
+            })
+            .collect()
+    }
+
+    pub fn poll_timeout(&self) -> Duration {
+        Duration::from_secs(self.poll_timeout * 60)
+    }
+
+    pub fn read_ca(&self) -> Result<Option<Vec<u8>>> {
+        match &self.ca {
+            Some(ca) => {
+                let ca = read(ca).with_context(|| format!("{{acme_ca={}}}", ca.display()))?;
+                Ok(Some(ca))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn names(&self, general: &str) -> Result<Vec<String>> {
+        let primary = self.name.as_deref().unwrap_or(general);
+        let wildcard = format!("*.{}", primary.trim_end_matches('.'));
+        let wildcard = Some(wildcard.as_str()).filter(|_| self.wildcard);
+        let sans = wildcard
+            .into_iter()
+            .chain(self.sans.iter().map(String::as_str));
+
+        let mut names: Vec<String> = Vec::with_capacity(self.sans.len() + 2);
+        for name in std::iter::once(primary).chain(sans) {
+            let parsed = Name::from_str(name)?;
+            // the wildcard label does not count
+            if parsed.num_labels() < 2 {
+                return Err(anyhow!("Acme name {} is not a domain", name));
+            }
+            if parsed.iter().skip(1).any(|label| label == b"*") {
+                return Err(anyhow!(
+                    "Acme name {} has a wildcard which is not leftmost",
+                    name
+                ));
+            }
+            let name = parsed.to_lowercase().to_string();
+            let name = name.trim_end_matches('.').to_owned();
+            if names.contains(&name) {
+                return Err(anyhow!("Acme name {} is configured twice", name));
+            }
+            names.push(name);
+        }
+
+        Ok(names)
+    }
+}
+
+// base64 encoded key encryption key, read when the server starts
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    File(PathBuf),
+    Env(String),
+}
+
+impl KeySource {
+    fn read(&self) -> Result<Vec<u8>> {
+        let key = match self {
+            KeySource::File(path) => read_to_string(path)
+                .with_context(|| format!("{{encryption_key={}}}", path.display()))?,
+            KeySource::Env(name) => {
+                env::var(name).with_context(|| format!("{{encryption_key={}}}", name))?
+            }
+        };
+        base64::decode(key.trim()).context("Encryption key is not base64")
+    }
+}
+
+// private keys in the database are encrypted with key, only enabled if this section is present
+// previous keys are only used to decrypt values which were not rotated to key yet
+#[derive(Deserialize, Debug, Clone)]
+pub struct Encryption {
+    pub key: KeySource,
+    #[serde(default)]
+    pub previous: Vec<KeySource>,
+}
+
+impl Encryption {
+    pub fn keyring(&self) -> Result<Keyring> {
+        let previous = self
+            .previous
+            .iter()
+            .map(KeySource::read)
+            .collect::<Result<Vec<_>>>()?;
+        Keyring::new(&self.key.read()?, &previous)
+    }
+}
+
+// clients from one of the networks get exactly the records of the view
+// with fallback names without records in the view get the top level records
+#[derive(Deserialize, Debug, Clone)]
+pub struct View {
+    pub networks: Vec<IpNet>,
+    #[serde(default, deserialize_with = "records::deserialize")]
+    pub records: PreconfiguredRecords,
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+impl View {
+    pub fn records(&self, records: &PreconfiguredRecords) -> PreconfiguredRecords {
+        if !self.fallback {
+            return self.records.clone();
+        }
+
+        let mut records = records.clone();
+        records.extend(self.records.clone());
+        records
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub general: General,
     pub api: Api,
+    #[serde(default)]
+    pub acme: Acme,
+    pub tls: Option<Tls>,
     #[serde(default, deserialize_with = "records::deserialize")]
     pub records: PreconfiguredRecords,
+    pub transfer: Option<Transfer>,
+    pub update: Option<Update>,
+    #[serde(default, deserialize_with = "tsig::deserialize")]
+    pub tsig: TsigKeys,
+    pub rrl: Option<Rrl>,
+    pub dnstap: Option<Dnstap>,
+    pub caa: Option<Caa>,
+    #[serde(default)]
+    pub views: HashMap<String, View>,
+    pub encryption: Option<Encryption>,
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        if let Some(tls) = &self.tls {
+            if tls.interval == 0 {
+                return Err(anyhow!("Tls interval has to be at least one second"));
+            }
+            if tls.cert.is_some() != tls.key.is_some() {
+                return Err(anyhow!("Tls cert and key have to be set together"));
+            }
+            if tls.cert.is_none() && tls.sni.is_empty() {
+                return Err(anyhow!("Tls needs a cert or sni certs"));
+            }
+        }
+
+        self.acme.contact()?;
+        self.acme.names(&self.general.name)?;
+        if let Some(eab) = &self.acme.eab {
+            if eab.kid.is_empty() || eab.hmac_key.is_empty() {
+                return Err(anyhow!("Acme eab needs a kid and a hmac key"));
+            }
+        }
+        // another replica takes over an update which is older than an hour
+        if self.acme.poll_timeout == 0 || self.acme.poll_timeout >= 60 {
+            return Err(anyhow!(
+                "Acme poll timeout has to be between 1 and 59 minutes"
+            ));
+        }
+
+        if let Some(rrl) = &self.rrl {
+            if rrl.ipv4_prefix > 32 || rrl.ipv6_prefix > 128 {
+                return Err(anyhow!("Rrl prefix is too long"));
+            }
+        }
+
+        if let Some(caa) = &self.caa {
+            caa.rdata(&self.general.acme)?;
+        }
+
+        if let Some((name, _)) = self.views.iter().find(|(_, view)| view.networks.is_empty()) {
+            return Err(anyhow!("View {} needs at least one network", name));
+        }
+
+        // views are not ordered, so the most specific network has to belong to a single view
+        let mut networks = HashMap::new();
+        for (name, view) in &self.views {
+            for network in &view.networks {
+                match networks.insert(network.trunc(), name) {
+                    Some(other) if other != name => {
+                        return Err(anyhow!(
+                            "Network {} is in view {} and {}",
+                            network.trunc(),
+                            other.min(name),
+                            other.max(name)
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(encryption) = &self.encryption {
+            if encryption.previous.contains(&encryption.key) {
+                return Err(anyhow!("Encryption key is also a previous key"));
+            }
+        }
+
+        if let Some(dnstap) = &self.dnstap {
+            if dnstap.socket.is_some() == dnstap.file.is_some() {
+                return Err(anyhow!("Dnstap needs either a socket or a file"));
+            }
+        }
+
+        let transfer = match &self.transfer {
+            Some(transfer) => transfer,
+            None => return Ok(()),
+        };
+
+        if transfer.allow.is_empty() && transfer.keys.is_empty() {
+            return Err(anyhow!(
+                "Transfer needs at least one allowed network or key"
+            ));
+        }
+
+        match transfer
+            .keys
+            .iter()
+            .find(|key| !self.tsig.contains_key(key))
+        {
+            Some(key) => Err(anyhow!("Transfer key {} is not configured in tsig", key)),
+            None => Ok(()),
+        }
+    }
 }
 
 const DEFAULT_CONFIG_PATH: &str = "config.toml";
@@ -61,6 +580,7 @@ pub fn load_config(config_path: Option<String>) -> Result<Config> {
 
     trace!("Start deserializing config file");
     let config = toml::de::from_slice::<Config>(&file)?;
+    config.validate()?;
     // redact db information
     let config_str = format!("{:?}", config).replace(&config.general.db, "******");
     info!(config = %config_str, "Deserialized config");
@@ -70,10 +590,31 @@ pub fn load_config(config_path: Option<String>) -> Result<Config> {
 
 #[cfg(test)]
 mod tests {
+    use ipnet::IpNet;
+    use std::net::SocketAddr;
     use std::path::Path;
+    use std::time::Duration;
     use tracing_test::traced_test;
 
-    use super::{default_acme, load_config, DEFAULT_ACME};
+    use std::str::FromStr;
+    use trust_dns_server::proto::rr::rdata::caa::{KeyValue, Value};
+    use trust_dns_server::proto::rr::Name;
+
+    use super::{default_acme, load_config, Config, KeySource, DEFAULT_ACME};
+    use crate::facade::Keyring;
+
+    const GENERAL: &str = r#"
+        [general]
+        dns = "0.0.0.0:53"
+        db = "postgres://root@localhost/acme"
+        name = "acme.example.com"
+
+        [api]
+    "#;
+
+    fn parse_config(rest: &str) -> Config {
+        toml::from_str(&format!("{}{}", GENERAL, rest)).unwrap()
+    }
 
     #[test]
     #[traced_test]
@@ -84,10 +625,388 @@ mod tests {
 
         // check if logs contain redacted db information
         let config = format!("{:?}", config);
-        let redacted_config = format!("{:?}", config).replace("postgres://root@localhost/acme", "******");
+        let redacted_config = config.replace("postgres://root@localhost/acme", "******");
         // make sure redaction worked
-        assert_eq!(config.len() > redacted_config.len());
-        assert!(logs_contain(&config));
+        assert!(config.len() > redacted_config.len());
+        assert!(logs_contain(&redacted_config));
+        assert!(!logs_contain("postgres://root@localhost/acme"));
+    }
+
+    #[test]
+    fn rrl_defaults() {
+        let config = parse_config("[rrl]");
+        let rrl = config.rrl.as_ref().unwrap();
+        assert_eq!(5, rrl.responses_per_second);
+        assert_eq!(2, rrl.slip);
+        assert_eq!(24, rrl.ipv4_prefix);
+        assert_eq!(56, rrl.ipv6_prefix);
+        assert!(config.validate().is_ok());
+
+        let config = parse_config("[rrl]\nipv4_prefix = 33");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn caa_issuer() {
+        let issuer = |acme| super::caa_issuer(acme).unwrap();
+        assert_eq!("letsencrypt.org", issuer(DEFAULT_ACME));
+        assert_eq!(
+            "letsencrypt.org",
+            issuer("https://acme-staging-v02.api.letsencrypt.org/directory")
+        );
+        assert_eq!("sectigo.com", issuer("https://acme.zerossl.com/v2/DV90"));
+        assert!(super::caa_issuer("https://acme.ca.example.com/directory").is_err());
+    }
+
+    #[test]
+    fn caa_validation() {
+        let config = parse_config("[caa]");
+        assert!(config.validate().is_ok());
+        let caa = config.caa.unwrap().rdata(DEFAULT_ACME).unwrap();
+        assert_eq!(
+            &Value::Issuer(Some(Name::from_str("letsencrypt.org").unwrap()), vec![]),
+            caa.value()
+        );
+
+        let account = "https://acme-v02.api.letsencrypt.org/acme/acct/123";
+        let config = parse_config(&format!("[caa]\naccount_uri = \"{}\"", account));
+        assert!(config.validate().is_ok());
+        let caa = config.caa.unwrap().rdata(DEFAULT_ACME).unwrap();
+        let options = vec![KeyValue::new("accounturi", account)];
+        assert_eq!(
+            &Value::Issuer(Some(Name::from_str("letsencrypt.org").unwrap()), options),
+            caa.value()
+        );
+
+        let config = parse_config("[caa]\naccount_uri = \"https://other.example.com/acct/1\"");
+        assert!(config.validate().is_err());
+        let config = parse_config("[caa]\nissuer = \"not a name..\"");
+        assert!(config.validate().is_err());
+
+        // unknown directories need an explicit issuer
+        let acme = "https://acme.ca.example.com/directory";
+        let caa = parse_config("[caa]").caa.unwrap();
+        assert!(caa.rdata(acme).is_err());
+        let caa = parse_config("[caa]\nissuer = \"ca.example.com\"")
+            .caa
+            .unwrap();
+        assert_eq!(
+            &Value::Issuer(Some(Name::from_str("ca.example.com").unwrap()), vec![]),
+            caa.rdata(acme).unwrap().value()
+        );
+    }
+
+    #[test]
+    fn dnstap_validation() {
+        let config = parse_config("[dnstap]\nsocket = \"/run/dnstap.sock\"\ntcp = false");
+        let dnstap = config.dnstap.as_ref().unwrap();
+        assert!(dnstap.udp);
+        assert!(!dnstap.tcp);
+        assert!(config.validate().is_ok());
+
+        assert!(parse_config("[dnstap]").validate().is_err());
+        let config = parse_config("[dnstap]\nsocket = \"/run/dnstap.sock\"\nfile = \"dnstap.log\"");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn acme_validation() {
+        let config = parse_config("");
+        assert!(config.acme.contact().unwrap().is_empty());
+        assert_eq!(
+            vec!["acme.example.com", "*.acme.example.com"],
+            config.acme.names(&config.general.name).unwrap()
+        );
+
+        let config = parse_config(
+            r#"
+            [acme]
+            contact = ["admin@example.com", "ops@example.com"]
+            name = "API.example.com."
+            sans = ["www.example.com"]
+            wildcard = false
+        "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            vec!["mailto:admin@example.com", "mailto:ops@example.com"],
+            config.acme.contact().unwrap()
+        );
+        assert_eq!(
+            vec!["api.example.com", "www.example.com"],
+            config.acme.names(&config.general.name).unwrap()
+        );
+
+        for contact in &[
+            "admin",
+            "@example.com",
+            "admin@",
+            "a,b@example.com",
+            "mailto:a@b.com",
+        ] {
+            let config = parse_config(&format!("[acme]\ncontact = [\"{}\"]", contact));
+            let error = config.validate().unwrap_err();
+            assert!(error.to_string().contains("is not an email"), "{}", contact);
+        }
+
+        let config = parse_config("[acme]\nsans = [\"Acme.example.com\"]");
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            "Acme name acme.example.com is configured twice",
+            error.to_string()
+        );
+        let config = parse_config("[acme]\nsans = [\"*.Acme.example.com\"]");
+        assert!(config.validate().is_err());
+        let config = parse_config("[acme]\nwildcard = false\nsans = [\"*.acme.example.com\"]");
+        assert!(config.validate().is_ok());
+        for san in &["com", "*.com", "www.*.example.com"] {
+            let config = parse_config(&format!("[acme]\nsans = [\"{}\"]", san));
+            assert!(config.validate().is_err(), "{}", san);
+        }
+        let config = parse_config("[acme]\nname = \"not a name..\"");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn acme_poll_timeout() {
+        let config = parse_config("");
+        assert_eq!(Duration::from_secs(600), config.acme.poll_timeout());
+
+        let config = parse_config("[acme]\npoll_timeout = 30");
+        assert!(config.validate().is_ok());
+        assert_eq!(Duration::from_secs(1800), config.acme.poll_timeout());
+        for timeout in &[0, 60] {
+            let config = parse_config(&format!("[acme]\npoll_timeout = {}", timeout));
+            assert!(config.validate().is_err(), "{}", timeout);
+        }
+    }
+
+    #[test]
+    fn acme_eab() {
+        let config = parse_config(
+            r#"
+            [acme]
+            ca = "pebble.crt"
+            [acme.eab]
+            kid = "kid-1"
+            hmac_key = "c2VjcmV0LWtleQ"
+        "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(Some(Path::new("pebble.crt")), config.acme.ca.as_deref());
+        let eab = config.acme.eab.as_ref().unwrap();
+        assert_eq!("kid-1", eab.kid);
+        assert_eq!(b"secret-key".to_vec(), eab.hmac_key);
+        assert!(!format!("{:?}", eab).contains("c2VjcmV0"));
+
+        // padding and url safe characters are accepted
+        let config = parse_config("[acme.eab]\nkid = \"kid-1\"\nhmac_key = \"-_8=\"");
+        assert_eq!(vec![0xfb, 0xff], config.acme.eab.unwrap().hmac_key);
+
+        let config = parse_config("[acme.eab]\nkid = \"\"\nhmac_key = \"c2VjcmV0\"");
+        assert!(config.validate().is_err());
+        let config = parse_config("[acme.eab]\nkid = \"kid-1\"\nhmac_key = \"\"");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn encryption() {
+        std::env::set_var(
+            "ACME_DNS_TEST_KEK",
+            " AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=\n",
+        );
+        let config = parse_config("[encryption]\nkey = { env = \"ACME_DNS_TEST_KEK\" }");
+        assert!(config.validate().is_ok());
+        let encryption = config.encryption.unwrap();
+        assert_eq!(
+            KeySource::Env("ACME_DNS_TEST_KEK".to_owned()),
+            encryption.key
+        );
+        assert!(encryption.previous.is_empty());
+        let keyring = encryption.keyring().unwrap();
+        let sealed = keyring.seal(b"aad", b"secret").unwrap();
+        let expected = Keyring::new(&[1; 32], &[]).unwrap();
+        assert_eq!(
+            b"secret".to_vec(),
+            expected.open(b"aad", sealed.as_bytes()).unwrap()
+        );
+
+        let config = parse_config(
+            r#"
+            [encryption]
+            key = { env = "ACME_DNS_TEST_KEK" }
+            previous = [{ file = "does-not-exist.key" }]
+        "#,
+        );
+        assert!(config.validate().is_ok());
+        assert!(config.encryption.unwrap().keyring().is_err());
+
+        let config = parse_config(
+            r#"
+            [encryption]
+            key = { env = "ACME_DNS_TEST_KEK" }
+            previous = [{ env = "ACME_DNS_TEST_KEK" }]
+        "#,
+        );
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn tls_validation() {
+        let config = parse_config("[tls]\ncert = \"tls.crt\"\nkey = \"tls.key\"");
+        assert!(config.validate().is_ok());
+        let tls = config.tls.unwrap();
+        assert!(tls.sni.is_empty());
+        assert_eq!(
+            Some(Path::new("tls.crt")),
+            tls.default_files()
+                .as_ref()
+                .map(|files| files.cert.as_path())
+        );
+
+        // sni certs on their own are served next to the acme cert
+        let config = parse_config(
+            r#"
+            [[tls.sni]]
+            cert = "internal.crt"
+            key = "internal.key"
+        "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(None, config.tls.unwrap().default_files());
+
+        let config = parse_config("[tls]\ncert = \"tls.crt\"");
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            "Tls cert and key have to be set together",
+            error.to_string()
+        );
+        let config = parse_config("[tls]");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn views_validation() {
+        let config = parse_config(
+            r#"
+            [views.internal]
+            networks = ["10.0.0.0/8"]
+
+            [views.internal.records."api.acme.example.com"]
+            A = [100, "10.0.0.10"]
+        "#,
+        );
+        assert!(config.validate().is_ok());
+        let view = &config.views["internal"];
+        assert_eq!(1, view.records.len());
+
+        let config = parse_config("[views.internal]\nnetworks = []");
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            "View internal needs at least one network",
+            error.to_string()
+        );
+
+        let config = parse_config(
+            r#"
+            [views.internal]
+            networks = ["10.0.0.0/8"]
+            [views.office]
+            networks = ["10.1.0.0/16", "10.0.0.1/8"]
+        "#,
+        );
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            "Network 10.0.0.0/8 is in view internal and office",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn view_records() {
+        let config = parse_config(
+            r#"
+            [records."www.acme.example.com"]
+            A = [100, "1.1.1.1"]
+
+            [views.internal]
+            networks = ["10.0.0.0/8"]
+            [views.internal.records."api.acme.example.com"]
+            A = [100, "10.0.0.10"]
+
+            [views.fallback]
+            networks = ["192.168.0.0/16"]
+            fallback = true
+            [views.fallback.records."api.acme.example.com"]
+            A = [100, "192.168.0.10"]
+        "#,
+        );
+        let www = Name::from_str("www.acme.example.com.").unwrap();
+        let api = Name::from_str("api.acme.example.com.").unwrap();
+
+        let records = config.views["internal"].records(&config.records);
+        assert!(records.contains_key(&api));
+        assert!(!records.contains_key(&www));
+
+        let records = config.views["fallback"].records(&config.records);
+        assert!(records.contains_key(&api));
+        assert!(records.contains_key(&www));
+    }
+
+    #[test]
+    fn update_allow() {
+        assert!(parse_config("").update.is_none());
+        let config = parse_config("[update]");
+        assert!(config.update.unwrap().allow.is_empty());
+
+        let config = parse_config("[update]\nallow = [\"10.0.0.0/8\"]");
+        let allow = config.update.unwrap().allow;
+        assert_eq!(vec!["10.0.0.0/8".parse::<IpNet>().unwrap()], allow);
+    }
+
+    #[test]
+    fn transfer_validation() {
+        let config = parse_config("");
+        assert!(config.validate().is_ok());
+
+        let config = parse_config(
+            r#"
+            [transfer]
+            allow = ["10.0.0.0/8"]
+            notify = ["10.0.0.1:53"]
+        "#,
+        );
+        assert!(config.validate().is_ok());
+        let notify = &config.transfer.unwrap().notify;
+        assert_eq!(&["10.0.0.1:53".parse::<SocketAddr>().unwrap()], &notify[..]);
+
+        let config = parse_config("[transfer]");
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("at least one"));
+
+        let config = parse_config(
+            r#"
+            [transfer]
+            keys = ["missing"]
+        "#,
+        );
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            "Transfer key missing. is not configured in tsig",
+            error.to_string()
+        );
+
+        let config = parse_config(
+            r#"
+            [transfer]
+            keys = ["transfer"]
+
+            [tsig.transfer]
+            algorithm = "hmac-sha256"
+            secret = "c2VjcmV0"
+        "#,
+        );
+        assert!(config.validate().is_ok());
     }
 
     #[test]
@@ -4,7 +4,8 @@ use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::str::FromStr;
 use std::sync::Arc;
-use trust_dns_server::proto::rr::rdata::TXT;
+use trust_dns_server::proto::rr::rdata::caa::{read_iodef, read_issuer};
+use trust_dns_server::proto::rr::rdata::{CAA, TXT};
 use trust_dns_server::proto::rr::{Name, RData, RecordSet, RecordType};
 
 pub type PreconfiguredRecords = HashMap<Name, HashMap<RecordType, Arc<RecordSet>>>;
@@ -69,6 +70,7 @@ impl<'de> DeserializeSeed<'de> for RecordDataSeed {
                         "TXT" => RecordType::TXT,
                         "A" => RecordType::A,
                         "CNAME" => RecordType::CNAME,
+                        "CAA" => RecordType::CAA,
                         _ => return Err(DeError::custom("Could not find RecordType")),
                     };
 
@@ -117,6 +119,7 @@ impl<'de> DeserializeSeed<'de> for RecordSeed {
                         RecordType::A => RData::A(data.parse().map_err(DeError::custom)?),
                         RecordType::TXT => RData::TXT(TXT::new(vec![data.into()])),
                         RecordType::CNAME => RData::CNAME(data.parse().map_err(DeError::custom)?),
+                        RecordType::CAA => RData::CAA(parse_caa(data).map_err(DeError::custom)?),
                         _ => return Err(DeError::custom("Invalid key")),
                     };
                     match record_set.add_rdata(rdata) {
@@ -138,9 +141,39 @@ impl<'de> DeserializeSeed<'de> for RecordSeed {
     }
 }
 
+// same format as in a zone file, for example 0 issue "letsencrypt.org"
+fn parse_caa(data: &str) -> Result<CAA, String> {
+    let mut parts = data.trim().splitn(3, char::is_whitespace);
+    let (flags, tag, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(flags), Some(tag), Some(value)) => (flags, tag, value.trim().trim_matches('"')),
+        _ => return Err(format!("Invalid CAA record {}", data)),
+    };
+    let issuer_critical = match flags.parse::<u8>() {
+        Ok(flags) => flags & 0b1000_0000 != 0,
+        Err(e) => return Err(format!("Invalid CAA flags {}", e)),
+    };
+
+    match tag {
+        "issue" | "issuewild" => {
+            let (name, options) = read_issuer(value.as_bytes()).map_err(|e| e.to_string())?;
+            match tag {
+                "issue" => Ok(CAA::new_issue(issuer_critical, name, options)),
+                _ => Ok(CAA::new_issuewild(issuer_critical, name, options)),
+            }
+        }
+        "iodef" => {
+            let url = read_iodef(value.as_bytes()).map_err(|e| e.to_string())?;
+            Ok(CAA::new_iodef(issuer_critical, url))
+        }
+        _ => Err(format!("Unsupported CAA tag {}", tag)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{deserialize, PreconfiguredRecords};
+    use trust_dns_server::proto::rr::rdata::caa::{KeyValue, Property, Value};
+
+    use super::{deserialize, parse_caa, PreconfiguredRecords};
     use serde::Deserialize;
     use serde_test::Token;
 
@@ -188,6 +221,27 @@ mod tests {
         ]
     }
 
+    #[test]
+    fn parse_caa_works() {
+        let caa =
+            parse_caa(r#"0 issue "letsencrypt.org; accounturi=https://acme/acct/1""#).unwrap();
+        assert!(!caa.issuer_critical());
+        assert_eq!(&Property::Issue, caa.tag());
+        let options = vec![KeyValue::new("accounturi", "https://acme/acct/1")];
+        assert_eq!(
+            &Value::Issuer(Some("letsencrypt.org".parse().unwrap()), options),
+            caa.value()
+        );
+
+        let caa = parse_caa("128 issuewild ;").unwrap();
+        assert!(caa.issuer_critical());
+        assert_eq!(&Value::Issuer(None, vec![]), caa.value());
+
+        assert!(parse_caa("0 iodef mailto:security@example.com").is_ok());
+        assert!(parse_caa("0 issue").is_err());
+        assert!(parse_caa("0 unknown value").is_err());
+    }
+
     // todo: renable this test
     #[test]
     fn deserialize_test() {
@@ -0,0 +1,212 @@
+use serde::de::{Error as DeError, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::str::FromStr;
+use trust_dns_server::proto::rr::Name;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TsigAlgorithm {
+    HmacSha1,
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HmacSha1 => "hmac-sha1.",
+            Self::HmacSha256 => "hmac-sha256.",
+            Self::HmacSha384 => "hmac-sha384.",
+            Self::HmacSha512 => "hmac-sha512.",
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = String;
+
+    // accepts the algorithm names with or without the trailing dot
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha1" => Ok(Self::HmacSha1),
+            "hmac-sha256" => Ok(Self::HmacSha256),
+            "hmac-sha384" => Ok(Self::HmacSha384),
+            "hmac-sha512" => Ok(Self::HmacSha512),
+            _ => Err(format!("Unsupported TSIG algorithm {}", s)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TsigAlgorithm {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <&str>::deserialize(deserializer)?
+            .parse()
+            .map_err(DeError::custom)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct TsigKey {
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+// never print the secret
+impl std::fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("algorithm", &self.algorithm)
+            .field("secret", &"******")
+            .finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for TsigKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct RawTsigKey<'a> {
+            algorithm: TsigAlgorithm,
+            secret: &'a str,
+        }
+
+        let RawTsigKey { algorithm, secret } = RawTsigKey::deserialize(deserializer)?;
+        let secret = base64::decode(secret).map_err(DeError::custom)?;
+
+        Ok(TsigKey { algorithm, secret })
+    }
+}
+
+pub type TsigKeys = HashMap<Name, TsigKey>;
+
+pub(super) fn deserialize_names<'de, D>(deserializer: D) -> Result<Vec<Name>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<&str>::deserialize(deserializer)?
+        .into_iter()
+        .map(|name| {
+            let mut name = Name::from_str(name).map_err(DeError::custom)?;
+            name.set_fqdn(true);
+            Ok(name)
+        })
+        .collect()
+}
+
+pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<TsigKeys, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct TsigKeysVisitor;
+    impl<'de> Visitor<'de> for TsigKeysVisitor {
+        type Value = TsigKeys;
+
+        fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+            formatter.write_str("TsigKeys")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut res = HashMap::with_capacity(map.size_hint().unwrap_or_default());
+            while let Some(key) = map.next_key::<&str>()? {
+                let mut name = Name::from_str(key).map_err(DeError::custom)?;
+                name.set_fqdn(true);
+                res.insert(name, map.next_value()?);
+            }
+
+            Ok(res)
+        }
+    }
+
+    deserializer.deserialize_map(TsigKeysVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error, Token};
+    use std::str::FromStr;
+    use trust_dns_server::proto::rr::Name;
+
+    use super::{deserialize, TsigAlgorithm, TsigKey, TsigKeys};
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct TsigKeysWrapper(#[serde(deserialize_with = "deserialize")] TsigKeys);
+
+    #[test]
+    fn algorithm_from_str() {
+        assert_eq!(Ok(TsigAlgorithm::HmacSha256), "hmac-sha256".parse());
+        assert_eq!(Ok(TsigAlgorithm::HmacSha512), "HMAC-SHA512.".parse());
+        assert!("hmac-md5".parse::<TsigAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn deserialize_test() {
+        let mut keys = TsigKeys::new();
+        keys.insert(
+            Name::from_str("transfer.").unwrap(),
+            TsigKey {
+                algorithm: TsigAlgorithm::HmacSha256,
+                secret: b"secret".to_vec(),
+            },
+        );
+
+        assert_de_tokens(
+            &TsigKeysWrapper(keys),
+            &[
+                Token::NewtypeStruct {
+                    name: "TsigKeysWrapper",
+                },
+                Token::Map { len: Some(1) },
+                Token::BorrowedStr("transfer"),
+                Token::Struct {
+                    name: "RawTsigKey",
+                    len: 2,
+                },
+                Token::BorrowedStr("algorithm"),
+                Token::BorrowedStr("hmac-sha256"),
+                Token::BorrowedStr("secret"),
+                Token::BorrowedStr("c2VjcmV0"),
+                Token::StructEnd,
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn deserialize_invalid_secret() {
+        assert_de_tokens_error::<TsigKey>(
+            &[
+                Token::Struct {
+                    name: "RawTsigKey",
+                    len: 2,
+                },
+                Token::BorrowedStr("algorithm"),
+                Token::BorrowedStr("hmac-sha256"),
+                Token::BorrowedStr("secret"),
+                Token::BorrowedStr("not base64!"),
+                Token::StructEnd,
+            ],
+            "Invalid byte 32, offset 3.",
+        );
+    }
+
+    #[test]
+    fn debug_redacts_secret() {
+        let key = TsigKey {
+            algorithm: TsigAlgorithm::HmacSha1,
+            secret: b"secret".to_vec(),
+        };
+        assert!(!format!("{:?}", key).contains("secret: [115"));
+        assert!(format!("{:?}", key).contains("******"));
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use futures_util::TryFutureExt;
+use futures_util::{future, TryFutureExt};
 use std::io::{Error as IoError, ErrorKind};
 use std::net::IpAddr::V4;
 use std::str;
@@ -8,16 +8,18 @@ use std::sync::Arc;
 use tracing::field::display;
 use tracing::{debug, error, info, Instrument, Span};
 use trust_dns_server::authority::{
-    AuthorityObject, BoxedLookupFuture, LookupObject, LookupRecords, MessageRequest, UpdateResult,
-    ZoneType,
+    AuthLookup, AuthorityObject, BoxedLookupFuture, LookupError, LookupObject, LookupRecords,
+    MessageRequest, UpdateResult, ZoneType,
 };
 use trust_dns_server::client::op::LowerQuery;
 use trust_dns_server::client::rr::LowerName;
+use trust_dns_server::proto::op::ResponseCode;
 use trust_dns_server::proto::rr::dnssec::SupportedAlgorithms;
-use trust_dns_server::proto::rr::rdata::{SOA, TXT};
+use trust_dns_server::proto::rr::rdata::{CAA, SOA, TXT};
 use trust_dns_server::proto::rr::record_data::RData;
 use trust_dns_server::proto::rr::{Name, Record, RecordSet, RecordType};
 
+use super::cache::LookupCache;
 use crate::config::PreconfiguredRecords;
 use crate::facade::{CertFacade, Domain, DomainFacade};
 use crate::util::error;
@@ -27,21 +29,45 @@ pub struct DatabaseAuthority<F>(Arc<DatabaseAuthorityInner<F>>);
 struct DatabaseAuthorityInner<F> {
     lower: LowerName,
     facade: F,
+    cache: LookupCache,
     records: PreconfiguredRecords,
     supported_algorithms: SupportedAlgorithms,
+    transfer: bool,
 }
 
-impl<F> DatabaseAuthority<F> {
-    pub fn new(facade: F, name: &str, records: PreconfiguredRecords) -> Box<Self> {
+impl<F: DomainFacade> DatabaseAuthority<F> {
+    pub fn new(
+        facade: F,
+        name: &str,
+        mut records: PreconfiguredRecords,
+        transfer: bool,
+        caa: Option<CAA>,
+    ) -> Box<Self> {
         // todo: remove unwrap
-        let lower = LowerName::from(Name::from_str(name).unwrap());
+        let origin = Name::from_str(name).unwrap();
         // todo: remove unwrap
 
+        // explicit caa records from the config take precedence
+        if let Some(caa) = caa {
+            let mut name = origin.clone();
+            name.set_fqdn(true);
+            let record = Record::from_rdata(name.clone(), 100, RData::CAA(caa));
+            records
+                .entry(name)
+                .or_default()
+                .entry(RecordType::CAA)
+                .or_insert_with(|| Arc::new(RecordSet::from(record)));
+        }
+        let lower = LowerName::from(origin);
+
+        let cache = LookupCache::new(facade.subscribe_domains());
         let inner = DatabaseAuthorityInner {
             lower,
             facade,
+            cache,
             records,
             supported_algorithms: SupportedAlgorithms::new(),
+            transfer,
         };
 
         Box::new(DatabaseAuthority(Arc::new(inner)))
@@ -83,6 +109,28 @@ async fn lookup_cname(record_set: &RecordSet) -> Result<Option<Arc<RecordSet>>>
     Ok(Some(Arc::new(record_set)))
 }
 
+// every value is served as its own txt record
+fn txt_records(name: Name, txt: &[String]) -> RecordSet {
+    let mut record_set = RecordSet::with_ttl(name, RecordType::TXT, 100);
+    for value in txt {
+        record_set.add_rdata(RData::TXT(TXT::new(vec![value.clone()])));
+    }
+    record_set
+}
+
+pub(super) fn soa_record(origin: Name, serial: u32) -> Record {
+    let soa = SOA::new(
+        origin.clone(),
+        origin.clone(),
+        serial,
+        28800,
+        7200,
+        604800,
+        86400,
+    );
+    Record::from_rdata(origin, 100, RData::SOA(soa))
+}
+
 impl<F: DomainFacade + CertFacade> DatabaseAuthorityInner<F> {
     #[tracing::instrument(err, skip(self, name, query_type))]
     async fn lookup_pre(
@@ -129,22 +177,104 @@ impl<F: DomainFacade + CertFacade> DatabaseAuthorityInner<F> {
 
     #[tracing::instrument(skip(self, name))]
     async fn acme_challenge(&self, name: Name) -> Result<LookupRecords> {
-        let cert = match self.facade.first_cert().await? {
+        let cert = match self.cache.first_cert(&self.facade).await? {
             Some(cert) => cert,
             None => return Err(anyhow!("First cert not found")),
         };
-        let domain = match self.facade.find_domain_by_id(&cert.domain).await {
+        let domain = match self
+            .cache
+            .find_domain_by_id(&self.facade, &cert.domain)
+            .await
+        {
             Ok(Some(domain)) => domain,
             Ok(None) => return Err(anyhow!("Domain not found {}", cert.domain)),
             Err(e) => return Err(e.into()),
         };
 
-        // todo: use match txt can be empty
-        let txt = TXT::new(vec![domain.txt.unwrap()]);
-        let record = Record::from_rdata(name, 100, RData::TXT(txt));
-        let record = Arc::new(RecordSet::from(record));
+        // apex and wildcard names share the challenge name so every proof has to be served
+        if domain.txt.is_empty() {
+            return Err(anyhow!("Domain {} has no challenge", domain.id));
+        }
+        let record = txt_records(name, &domain.txt);
+
+        Ok(LookupRecords::new(
+            false,
+            self.supported_algorithms,
+            Arc::new(record),
+        ))
+    }
 
-        Ok(LookupRecords::new(false, self.supported_algorithms, record))
+    // minimal any responses as described in RFC 8482 only contain a single rrset
+    // names which are not configured can only have a txt record
+    fn any_type(&self, name: &Name) -> RecordType {
+        self.records
+            .get(name)
+            .and_then(|records| {
+                records
+                    .keys()
+                    .min_by_key(|record_type| u16::from(**record_type))
+            })
+            .copied()
+            .unwrap_or(RecordType::TXT)
+    }
+
+    // the serial gets increased by the facade on every txt update
+    // so secondaries know when they have to transfer the zone again
+    async fn soa(&self) -> Record {
+        let origin = Name::from(&self.lower);
+        let serial = match self.facade.zone_serial().await {
+            Ok(serial) => serial,
+            Err(e) => {
+                error!("Could not get zone serial {}", e);
+                1
+            }
+        };
+
+        soa_record(origin, serial)
+    }
+
+    // the handler splits the zone into as many messages as needed
+    // ixfr requests also get the full zone which is allowed by RFC 1995
+    #[tracing::instrument(err, skip(self))]
+    async fn transfer(&self) -> Result<AuthLookup> {
+        let origin = Name::from(&self.lower);
+        let soa = Arc::new(RecordSet::from(self.soa().await));
+
+        let mut records = self
+            .records
+            .iter()
+            .filter(|(name, _)| origin.zone_of(name))
+            .flat_map(|(_, records)| records.values())
+            .filter(|record_set| record_set.record_type() != RecordType::SOA)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let challenge = Name::from_ascii("_acme-challenge")?.append_domain(&origin);
+        match self.acme_challenge(challenge).await {
+            Ok(LookupRecords::Records {
+                records: record, ..
+            }) => records.push(record),
+            Ok(_) => {}
+            Err(e) => debug!("No acme challenge in transfer {}", e),
+        }
+
+        for domain in self.facade.all_domains().await? {
+            if domain.txt.is_empty() {
+                continue;
+            }
+            let name = Name::from_ascii(&domain.id)?.append_domain(&origin);
+            let challenge = Name::from_ascii("_acme-challenge")?.append_domain(&name);
+            records.push(Arc::new(txt_records(name, &domain.txt)));
+            records.push(Arc::new(txt_records(challenge, &domain.txt)));
+        }
+        debug!(records = records.len(), "Built zone transfer");
+
+        let algorithms = self.supported_algorithms;
+        Ok(AuthLookup::AXFR {
+            start_soa: LookupRecords::new(false, algorithms, Arc::clone(&soa)),
+            records: LookupRecords::many(false, algorithms, records),
+            end_soa: LookupRecords::new(false, algorithms, soa),
+        })
     }
 }
 
@@ -152,7 +282,7 @@ impl<F: DomainFacade + CertFacade> DatabaseAuthorityInner<F> {
 impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
     for DatabaseAuthority<F>
 {
-    fn box_clone(&self) -> Box<(dyn AuthorityObject + 'static)> {
+    fn box_clone(&self) -> Box<dyn AuthorityObject + 'static> {
         Box::new(DatabaseAuthority(Arc::clone(&self.0)))
     }
 
@@ -161,9 +291,10 @@ impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
     }
 
     fn is_axfr_allowed(&self) -> bool {
-        false
+        self.0.transfer
     }
 
+    // updates are handled in the request handler as they need async access to the facade
     fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
         Ok(false)
     }
@@ -190,7 +321,10 @@ impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
     ) -> BoxedLookupFuture {
         let authority = Arc::clone(&self.0);
         let name = Name::from(query.name());
-        let query_type = query.query_type();
+        let query_type = match query.query_type() {
+            RecordType::ANY => authority.any_type(&name),
+            query_type => query_type,
+        };
         let span = Span::current();
         span.record("name", &display(&name));
         span.record("query_type", &display(&query_type));
@@ -200,6 +334,22 @@ impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
             return span.in_scope(|| self.soa());
         }
 
+        // access control happens in the request handler
+        if let RecordType::AXFR | RecordType::IXFR = query_type {
+            if !self.is_axfr_allowed() || !authority.lower.eq(query.name()) {
+                return BoxedLookupFuture::from(future::err(LookupError::from(
+                    ResponseCode::Refused,
+                )));
+            }
+
+            return BoxedLookupFuture::from(
+                async move { authority.transfer().await.map_err(error) }
+                    .map_ok(|res| Box::new(res) as Box<dyn LookupObject>)
+                    .inspect_err(|err| error!("{}", err))
+                    .instrument(span),
+            );
+        }
+
         BoxedLookupFuture::from(
             async move {
                 info!("Starting lookup");
@@ -217,24 +367,31 @@ impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
                     return Ok(pre);
                 }
 
-                if first == b"_acme-challenge" {
+                // _acme-challenge.<id>.<origin> serves the registration same as <id>.<origin>
+                let base = name.base_name();
+                let first = if first != b"_acme-challenge" {
+                    first
+                } else if name.num_labels() == authority.lower.num_labels() + 2 {
+                    base.iter().next().unwrap_or_default()
+                } else {
                     return authority.acme_challenge(name).await.map_err(error);
-                }
+                };
 
                 let first = match str::from_utf8(first) {
                     Ok(first) => first,
                     Err(e) => return Err(error(e)),
                 };
 
-                let txt = match authority.facade.find_domain_by_id(first).await {
-                    Ok(Some(Domain { txt: Some(txt), .. })) => txt,
-                    Ok(Some(Domain { txt: None, .. })) => return Ok(LookupRecords::Empty),
+                let domain = authority.cache.find_domain_by_id(&authority.facade, first);
+                let txt = match domain.await {
+                    Ok(Some(Domain { txt, .. })) if txt.is_empty() => {
+                        return Ok(LookupRecords::Empty)
+                    }
+                    Ok(Some(Domain { txt, .. })) => txt,
                     Ok(None) => return Err(error(IoError::from(ErrorKind::NotFound))),
                     Err(e) => return Err(error(e)),
                 };
-                let txt = TXT::new(vec![txt]);
-                let record = Record::from_rdata(name, 100, RData::TXT(txt));
-                let record_set = Arc::new(RecordSet::from(record));
+                let record_set = Arc::new(txt_records(name, &txt));
 
                 Ok(LookupRecords::new(
                     false,
@@ -258,23 +415,12 @@ impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
     }
 
     // fix handling of this as this always take self.origin
-    // also admin is always same serial numbers need to match
     fn soa(&self) -> BoxedLookupFuture {
-        let origin: Name = self.origin().into();
+        let authority = Arc::clone(&self.0);
         let supported_algorithms = self.0.supported_algorithms;
         BoxedLookupFuture::from(
             async move {
-                let soa = SOA::new(
-                    origin.clone(),
-                    origin.clone(),
-                    1,
-                    28800,
-                    7200,
-                    604800,
-                    86400,
-                );
-                let record = Record::from_rdata(origin, 100, RData::SOA(soa));
-                let record_set = RecordSet::from(record);
+                let record_set = RecordSet::from(authority.soa().await);
                 let records =
                     LookupRecords::new(false, supported_algorithms, Arc::from(record_set));
                 let records = Box::new(records) as Box<dyn LookupObject>;
@@ -296,9 +442,297 @@ impl<F: DomainFacade + CertFacade + Send + Sync + 'static> AuthorityObject
 #[cfg(test)]
 mod tests {
     use crate::dns::authority::lookup_cname;
-    use std::net::Ipv4Addr;
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr};
     use std::str::FromStr;
-    use trust_dns_server::proto::rr::{Name, RData, Record, RecordType};
+    use std::sync::Arc;
+    use trust_dns_server::authority::{Catalog, MessageRequest};
+    use trust_dns_server::proto::op::{Edns, Message, Query};
+    use trust_dns_server::proto::rr::rdata::{CAA, TXT};
+    use trust_dns_server::proto::rr::{Name, RData, Record, RecordSet, RecordType};
+    use trust_dns_server::proto::serialize::binary::BinDecodable;
+    use trust_dns_server::server::{Request, RequestHandler};
+
+    use super::DatabaseAuthority;
+    use crate::config::PreconfiguredRecords;
+    use crate::dns::response::ResponseEncoder;
+    use crate::dns::tsig::tests::TestResponseHandler;
+    use crate::facade::{Cert, CertFacade, Domain, DomainFacade, InMemoryFacade, State};
+
+    fn records() -> PreconfiguredRecords {
+        let mut records = PreconfiguredRecords::new();
+        for name in &["acme.example.com.", "www.acme.example.com.", "example.org."] {
+            let name = Name::from_str(name).unwrap();
+            let record = Record::from_rdata(name.clone(), 100, RData::A([1, 1, 1, 1].into()));
+            let mut record_sets = HashMap::new();
+            record_sets.insert(RecordType::A, Arc::new(RecordSet::from(record)));
+            records.insert(name, record_sets);
+        }
+
+        records
+    }
+
+    fn insert(records: &mut PreconfiguredRecords, name: &str, rdata: RData) {
+        let name = Name::from_str(name).unwrap();
+        let record = Record::from_rdata(name.clone(), 100, rdata);
+        records
+            .entry(name)
+            .or_default()
+            .insert(record.record_type(), Arc::new(RecordSet::from(record)));
+    }
+
+    // the request goes through a catalog like it would in the server
+    async fn query(
+        records: PreconfiguredRecords,
+        name: &str,
+        query_type: RecordType,
+        max_payload: Option<u16>,
+        caa: Option<CAA>,
+    ) -> Message {
+        let facade = InMemoryFacade::default();
+        let domain = Domain {
+            id: "first".to_owned(),
+            username: "first".to_owned(),
+            password: "password".to_owned(),
+            txt: vec!["challenge".to_owned()],
+        };
+        facade.create_domain(&domain).await.unwrap();
+
+        let authority = DatabaseAuthority::new(facade, "acme.example.com", records, false, caa);
+        let mut catalog = Catalog::new();
+        catalog.upsert(Name::root().into(), authority);
+
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+        if let Some(max_payload) = max_payload {
+            let mut edns = Edns::new();
+            edns.set_max_payload(max_payload);
+            message.set_edns(edns);
+        }
+        let request = Request {
+            message: MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap(),
+            src: SocketAddr::from_str("10.0.0.1:5353").unwrap(),
+        };
+
+        let sent = TestResponseHandler::default();
+        let max_payload = Some(request.message.max_payload());
+        let response_handle = ResponseEncoder::new(sent.clone(), max_payload);
+        catalog.handle_request(request, response_handle).await;
+
+        let response = sent.0.lock().pop().unwrap();
+        Message::from_vec(&response).unwrap()
+    }
+
+    fn types(message: &Message) -> Vec<RecordType> {
+        message
+            .answers()
+            .iter()
+            .map(Record::record_type)
+            .collect::<Vec<_>>()
+    }
+
+    #[tokio::test]
+    async fn any_returns_single_rrset() {
+        let mut records = records();
+        let txt = RData::TXT(TXT::new(vec!["static".to_owned()]));
+        insert(&mut records, "acme.example.com.", txt);
+
+        let response = query(
+            records.clone(),
+            "acme.example.com.",
+            RecordType::ANY,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(vec![RecordType::A], types(&response));
+
+        let response = query(
+            records,
+            "first.acme.example.com.",
+            RecordType::ANY,
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(vec![RecordType::TXT], types(&response));
+    }
+
+    #[tokio::test]
+    async fn udp_payload_size_is_honoured() {
+        let mut records = records();
+        let strings = (0..20).map(|i| format!("{:040}", i)).collect::<Vec<_>>();
+        insert(
+            &mut records,
+            "big.acme.example.com.",
+            RData::TXT(TXT::new(strings)),
+        );
+
+        // without edns only 512 bytes are allowed
+        let response = query(
+            records.clone(),
+            "big.acme.example.com.",
+            RecordType::TXT,
+            None,
+            None,
+        )
+        .await;
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+
+        let response = query(
+            records.clone(),
+            "big.acme.example.com.",
+            RecordType::TXT,
+            Some(4096),
+            None,
+        )
+        .await;
+        assert!(!response.truncated());
+        assert_eq!(vec![RecordType::TXT], types(&response));
+        assert_eq!(4096, response.edns().unwrap().max_payload());
+
+        let response = query(records, "acme.example.com.", RecordType::A, None, None).await;
+        assert!(!response.truncated());
+        assert_eq!(vec![RecordType::A], types(&response));
+    }
+
+    #[tokio::test]
+    async fn caa_is_synthesized() {
+        let issuer = Name::from_str("letsencrypt.org").ok();
+        let caa = CAA::new_issue(false, issuer, vec![]);
+        let response = query(
+            records(),
+            "acme.example.com.",
+            RecordType::CAA,
+            None,
+            Some(caa.clone()),
+        )
+        .await;
+        let answers = response.answers();
+        assert_eq!(1, answers.len());
+        assert_eq!(&RData::CAA(caa.clone()), answers[0].rdata());
+
+        // configured records win
+        let mut records = records();
+        let configured = CAA::new_issue(false, Name::from_str("pki.goog").ok(), vec![]);
+        insert(
+            &mut records,
+            "acme.example.com.",
+            RData::CAA(configured.clone()),
+        );
+        let response = query(
+            records,
+            "acme.example.com.",
+            RecordType::CAA,
+            None,
+            Some(caa),
+        )
+        .await;
+        let answers = response.answers();
+        assert_eq!(1, answers.len());
+        assert_eq!(&RData::CAA(configured), answers[0].rdata());
+    }
+
+    #[tokio::test]
+    async fn registration_challenge_is_served() {
+        let name = "_acme-challenge.first.acme.example.com.";
+        let response = query(records(), name, RecordType::TXT, None, None).await;
+        let answers = response.answers();
+        assert_eq!(1, answers.len());
+        assert_eq!(name, answers[0].name().to_string());
+        assert_eq!(
+            &RData::TXT(TXT::new(vec!["challenge".to_owned()])),
+            answers[0].rdata()
+        );
+    }
+
+    #[tokio::test]
+    async fn transfer_contains_zone() {
+        let facade = InMemoryFacade::default();
+        let mut domain = Domain {
+            id: "first".to_owned(),
+            username: "first".to_owned(),
+            password: "password".to_owned(),
+            txt: vec![],
+        };
+        facade.create_domain(&domain).await.unwrap();
+        domain.txt = vec!["apex".to_owned(), "wildcard".to_owned()];
+        facade.update_domain(&domain).await.unwrap();
+
+        let empty = Domain {
+            id: "second".to_owned(),
+            username: "second".to_owned(),
+            password: "password".to_owned(),
+            txt: vec![],
+        };
+        facade.create_domain(&empty).await.unwrap();
+
+        let authority = DatabaseAuthority::new(facade, "acme.example.com", records(), true, None);
+        let lookup = authority.0.transfer().await.unwrap();
+        let records = lookup.iter().collect::<Vec<_>>();
+
+        let serials = [records[0], records[records.len() - 1]]
+            .iter()
+            .map(|record| match record.rdata() {
+                RData::SOA(soa) => soa.serial(),
+                _ => panic!("Transfer does not start and end with a soa"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![2, 2], serials);
+
+        let mut names = records[1..records.len() - 1]
+            .iter()
+            .map(|record| record.name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            vec![
+                "_acme-challenge.first.acme.example.com.",
+                "_acme-challenge.first.acme.example.com.",
+                "acme.example.com.",
+                "first.acme.example.com.",
+                "first.acme.example.com.",
+                "www.acme.example.com."
+            ],
+            names
+        );
+    }
+
+    #[tokio::test]
+    async fn every_challenge_is_served() {
+        let facade = InMemoryFacade::default();
+        let domain = Domain {
+            id: "cert".to_owned(),
+            username: "cert".to_owned(),
+            password: "password".to_owned(),
+            txt: vec!["apex".to_owned(), "wildcard".to_owned()],
+        };
+        facade.create_domain(&domain).await.unwrap();
+        let cert = Cert {
+            id: "cert".to_owned(),
+            update: 0,
+            state: State::Updating,
+            cert: None,
+            private: None,
+            ocsp: None,
+            domain: domain.id.clone(),
+        };
+        facade.create_cert(&cert).await.unwrap();
+
+        let authority = DatabaseAuthority::new(facade, "acme.example.com", records(), false, None);
+        let name = Name::from_str("_acme-challenge.acme.example.com.").unwrap();
+        let lookup = authority.0.acme_challenge(name).await.unwrap();
+        let mut proofs = lookup
+            .iter()
+            .map(|record| match record.rdata() {
+                RData::TXT(txt) => txt.to_string(),
+                _ => panic!("Challenge is not a txt record"),
+            })
+            .collect::<Vec<_>>();
+        proofs.sort();
+        assert_eq!(vec!["apex", "wildcard"], proofs);
+    }
 
     #[tokio::test]
     async fn lookup_cname_works() {
@@ -0,0 +1,296 @@
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::broadcast::Receiver;
+use tracing::debug;
+
+use crate::facade::{Cert, CertFacade, Domain, DomainChange, DomainFacade};
+
+lazy_static! {
+    static ref DNS_CACHE_HIT_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dns_cache_hit_counter",
+        "Sum of DNS lookups answered from the cache",
+        &["lookup"]
+    )
+    .unwrap();
+    static ref DNS_CACHE_MISS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dns_cache_miss_counter",
+        "Sum of DNS lookups which had to query the database",
+        &["lookup"]
+    )
+    .unwrap();
+}
+
+const TTL: Duration = Duration::from_secs(5);
+const CAPACITY: usize = 10_000;
+
+struct Entry<T> {
+    value: T,
+    expires: Instant,
+}
+
+impl<T: Clone> Entry<T> {
+    fn get(&self, now: Instant) -> Option<T> {
+        match now < self.expires {
+            true => Some(self.value.clone()),
+            false => None,
+        }
+    }
+}
+
+struct Inner {
+    changes: Receiver<DomainChange>,
+    // gets increased on every invalidation so lookups started before it don't get cached
+    generation: u64,
+    first_cert: Option<Entry<Option<Cert>>>,
+    domains: HashMap<String, Entry<Option<Domain>>>,
+    // every cached id with its expiry, the oldest first
+    order: VecDeque<(String, Instant)>,
+}
+
+impl Inner {
+    // changes get sent by the facade after the update was committed
+    fn invalidate(&mut self) {
+        loop {
+            match self.changes.try_recv() {
+                Ok(DomainChange::Domain(id)) => {
+                    debug!(%id, "Invalidated cached domain");
+                    self.domains.remove(&id);
+                    self.generation += 1;
+                }
+                Ok(DomainChange::All) | Err(TryRecvError::Lagged(_)) => {
+                    debug!("Missed domain changes, clearing cache");
+                    self.domains.clear();
+                    self.order.clear();
+                    self.generation += 1;
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return,
+            }
+        }
+    }
+
+    // removes expired domains and the oldest ones until there is room for another
+    fn evict(&mut self, now: Instant, capacity: usize) {
+        while let Some((_, expires)) = self.order.front() {
+            if now < *expires && self.domains.len() < capacity {
+                break;
+            }
+
+            if let Some((id, expires)) = self.order.pop_front() {
+                // the domain could have been invalidated and cached again since
+                if self.domains.get(&id).map(|entry| entry.expires) == Some(expires) {
+                    self.domains.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+// sits in front of the facade lookups done for every challenge query
+pub(super) struct LookupCache {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl LookupCache {
+    pub(super) fn new(changes: Receiver<DomainChange>) -> Self {
+        LookupCache::with_limits(changes, TTL, CAPACITY)
+    }
+
+    fn with_limits(changes: Receiver<DomainChange>, ttl: Duration, capacity: usize) -> Self {
+        let inner = Inner {
+            changes,
+            generation: 0,
+            first_cert: None,
+            domains: HashMap::new(),
+            order: VecDeque::new(),
+        };
+
+        LookupCache {
+            ttl,
+            capacity,
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub(super) async fn first_cert<F: CertFacade>(
+        &self,
+        facade: &F,
+    ) -> Result<Option<Cert>, sqlx::Error> {
+        let generation = {
+            let mut inner = self.inner.lock();
+            inner.invalidate();
+            let now = Instant::now();
+            if let Some(cert) = inner.first_cert.as_ref().and_then(|entry| entry.get(now)) {
+                DNS_CACHE_HIT_COUNTER.with_label_values(&["cert"]).inc();
+                return Ok(cert);
+            }
+            inner.generation
+        };
+
+        DNS_CACHE_MISS_COUNTER.with_label_values(&["cert"]).inc();
+        let cert = facade.first_cert().await?;
+
+        let mut inner = self.inner.lock();
+        inner.invalidate();
+        if inner.generation == generation {
+            inner.first_cert = Some(Entry {
+                value: cert.clone(),
+                expires: Instant::now() + self.ttl,
+            });
+        }
+
+        Ok(cert)
+    }
+
+    pub(super) async fn find_domain_by_id<F: DomainFacade>(
+        &self,
+        facade: &F,
+        id: &str,
+    ) -> Result<Option<Domain>, sqlx::Error> {
+        let generation = {
+            let mut inner = self.inner.lock();
+            inner.invalidate();
+            let now = Instant::now();
+            if let Some(domain) = inner.domains.get(id).and_then(|entry| entry.get(now)) {
+                DNS_CACHE_HIT_COUNTER.with_label_values(&["domain"]).inc();
+                return Ok(domain);
+            }
+            inner.generation
+        };
+
+        DNS_CACHE_MISS_COUNTER.with_label_values(&["domain"]).inc();
+        let domain = facade.find_domain_by_id(id).await?;
+
+        let mut inner = self.inner.lock();
+        inner.invalidate();
+        if inner.generation != generation {
+            return Ok(domain);
+        }
+
+        // unknown ids get cached as well so random names cannot bypass the cache
+        // creating a domain invalidates its id, so new domains are found right away
+        let now = Instant::now();
+        inner.evict(now, self.capacity);
+        let expires = now + self.ttl;
+        inner.order.push_back((id.to_owned(), expires));
+        inner.domains.insert(
+            id.to_owned(),
+            Entry {
+                value: domain.clone(),
+                expires,
+            },
+        );
+
+        Ok(domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    use super::{LookupCache, DNS_CACHE_HIT_COUNTER};
+    use crate::facade::{Domain, DomainChange, DomainFacade, InMemoryFacade};
+
+    fn domain(id: &str) -> Domain {
+        Domain {
+            id: id.to_owned(),
+            username: id.to_owned(),
+            password: "password".to_owned(),
+            txt: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn update_invalidates_domain() {
+        let facade = InMemoryFacade::default();
+        let cache = LookupCache::new(facade.subscribe_domains());
+        let hits = DNS_CACHE_HIT_COUNTER.with_label_values(&["domain"]).get();
+
+        assert_eq!(
+            None,
+            cache.find_domain_by_id(&facade, "first").await.unwrap()
+        );
+
+        assert_eq!(
+            None,
+            cache.find_domain_by_id(&facade, "first").await.unwrap()
+        );
+        assert!(DNS_CACHE_HIT_COUNTER.with_label_values(&["domain"]).get() > hits);
+
+        // the missing domain is no longer cached once it got created
+        let mut domain = domain("first");
+        facade.create_domain(&domain).await.unwrap();
+        assert_eq!(
+            Some(domain.clone()),
+            cache.find_domain_by_id(&facade, "first").await.unwrap()
+        );
+
+        domain.txt = vec!["challenge".to_owned()];
+        facade.update_domain(&domain).await.unwrap();
+        let actual = cache.find_domain_by_id(&facade, "first").await.unwrap();
+        assert_eq!(Some(domain), actual);
+    }
+
+    // sent by the listener after it reconnected
+    #[tokio::test]
+    async fn resync_invalidates_every_domain() {
+        let facade = InMemoryFacade::default();
+        let (changes, receiver) = broadcast::channel(1);
+        let cache = LookupCache::new(receiver);
+
+        let mut domain = domain("first");
+        facade.create_domain(&domain).await.unwrap();
+        cache.find_domain_by_id(&facade, "first").await.unwrap();
+
+        // the cache does not see this change
+        domain.txt = vec!["challenge".to_owned()];
+        facade.update_domain(&domain).await.unwrap();
+        let actual = cache.find_domain_by_id(&facade, "first").await.unwrap();
+        assert!(actual.unwrap().txt.is_empty());
+
+        changes.send(DomainChange::All).unwrap();
+        let actual = cache.find_domain_by_id(&facade, "first").await.unwrap();
+        assert_eq!(Some(domain), actual);
+    }
+
+    #[tokio::test]
+    async fn entries_expire() {
+        let facade = InMemoryFacade::default();
+        let cache = LookupCache::with_limits(facade.subscribe_domains(), Duration::ZERO, 1);
+
+        assert_eq!(
+            None,
+            cache.find_domain_by_id(&facade, "first").await.unwrap()
+        );
+        let domain = domain("first");
+        facade.create_domain(&domain).await.unwrap();
+        assert_eq!(
+            Some(domain),
+            cache.find_domain_by_id(&facade, "first").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn capacity_is_bounded() {
+        let facade = InMemoryFacade::default();
+        let cache =
+            LookupCache::with_limits(facade.subscribe_domains(), Duration::from_secs(60), 2);
+
+        for id in &["first", "second", "third"] {
+            cache.find_domain_by_id(&facade, id).await.unwrap();
+        }
+
+        // only the oldest domain makes room for the new one
+        let inner = cache.inner.lock();
+        assert_eq!(2, inner.domains.len());
+        assert!(!inner.domains.contains_key("first"));
+        assert!(inner.domains.contains_key("third"));
+    }
+}
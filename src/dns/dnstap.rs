@@ -0,0 +1,453 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::cmp;
+use std::io::Result as IoResult;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{error, info};
+
+use super::handler::Transport;
+use super::response::ResponseSink;
+use crate::config::Dnstap;
+
+lazy_static! {
+    static ref DNS_DNSTAP_DROPPED_COUNTER: IntCounter = register_int_counter!(
+        "dns_dnstap_dropped_counter",
+        "Sum of dnstap frames dropped because the writer could not keep up"
+    )
+    .unwrap();
+}
+
+// frames get dropped instead of blocking the query path if the writer falls behind
+const CAPACITY: usize = 1024;
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// frame streams control frames
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+// values from dnstap.proto
+const DNSTAP_MESSAGE: u64 = 1;
+const AUTH_QUERY: u64 = 1;
+const AUTH_RESPONSE: u64 = 2;
+const INET: u64 = 1;
+const INET6: u64 = 2;
+const UDP: u64 = 1;
+const TCP: u64 = 2;
+
+// minimal protobuf encoding, only the wire types used by dnstap are needed
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint(field << 3 | wire_type);
+    }
+
+    fn uint(&mut self, field: u64, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn fixed32(&mut self, field: u64, value: u32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Query,
+    Response,
+}
+
+#[derive(Clone)]
+pub(super) struct DnstapLogger {
+    sender: Sender<Vec<u8>>,
+    identity: Option<String>,
+}
+
+impl DnstapLogger {
+    fn log(
+        &self,
+        kind: Kind,
+        src: SocketAddr,
+        transport: Transport,
+        query_time: Duration,
+        message: &[u8],
+    ) {
+        let mut inner = Protobuf(Vec::with_capacity(message.len() + 64));
+        let (family, address) = match src.ip() {
+            IpAddr::V4(ip) => (INET, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (INET6, ip.octets().to_vec()),
+        };
+        let protocol = match transport {
+            Transport::Udp => UDP,
+            Transport::Tcp => TCP,
+        };
+
+        let kind = match kind {
+            Kind::Query => AUTH_QUERY,
+            Kind::Response => AUTH_RESPONSE,
+        };
+        inner.uint(1, kind);
+        inner.uint(2, family);
+        inner.uint(3, protocol);
+        inner.bytes(4, &address);
+        inner.uint(6, u64::from(src.port()));
+        inner.uint(8, query_time.as_secs());
+        inner.fixed32(9, query_time.subsec_nanos());
+        if kind == AUTH_QUERY {
+            inner.bytes(10, message);
+        } else {
+            let response_time = timestamp();
+            inner.uint(12, response_time.as_secs());
+            inner.fixed32(13, response_time.subsec_nanos());
+            inner.bytes(14, message);
+        }
+
+        let mut dnstap = Protobuf(Vec::with_capacity(inner.0.len() + 64));
+        if let Some(identity) = &self.identity {
+            dnstap.bytes(1, identity.as_bytes());
+        }
+        dnstap.bytes(2, env!("CARGO_PKG_VERSION").as_bytes());
+        dnstap.bytes(14, &inner.0);
+        dnstap.uint(15, DNSTAP_MESSAGE);
+
+        if self.sender.try_send(dnstap.0).is_err() {
+            DNS_DNSTAP_DROPPED_COUNTER.inc();
+        }
+    }
+
+    // returns the query time so the response can reference it
+    // raw are the bytes the query was decoded from
+    pub(super) fn query(&self, raw: &[u8], src: SocketAddr, transport: Transport) -> Duration {
+        let query_time = timestamp();
+        self.log(Kind::Query, src, transport, query_time, raw);
+
+        query_time
+    }
+
+    pub(super) fn wrap<S: ResponseSink>(
+        &self,
+        sink: S,
+        src: SocketAddr,
+        transport: Transport,
+        query_time: Duration,
+    ) -> DnstapSink<S> {
+        DnstapSink {
+            sink,
+            logger: self.clone(),
+            src,
+            transport,
+            query_time,
+        }
+    }
+}
+
+fn timestamp() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[derive(Clone)]
+pub(super) struct DnstapSink<S> {
+    sink: S,
+    logger: DnstapLogger,
+    src: SocketAddr,
+    transport: Transport,
+    query_time: Duration,
+}
+
+// logs the response exactly as it gets sent
+impl<S: ResponseSink> ResponseSink for DnstapSink<S> {
+    fn send(&mut self, response: Vec<u8>) -> IoResult<()> {
+        self.logger.log(
+            Kind::Response,
+            self.src,
+            self.transport,
+            self.query_time,
+            &response,
+        );
+
+        self.sink.send(response)
+    }
+}
+
+fn control(control_type: u32) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    payload.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+    payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    payload.extend_from_slice(CONTENT_TYPE);
+
+    // a zero length marks a control frame
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+async fn read_control<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u32> {
+    if stream.read_u32().await? != 0 {
+        return Err(anyhow!("Expected control frame"));
+    }
+    let length = stream.read_u32().await?;
+    if !(4..=512).contains(&length) {
+        return Err(anyhow!("Invalid control frame length {}", length));
+    }
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload).await?;
+
+    Ok(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+enum Output {
+    Socket(PathBuf),
+    File(PathBuf),
+}
+
+pub(super) struct DnstapWriter {
+    output: Output,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl DnstapWriter {
+    // unix sockets use the bidirectional handshake
+    async fn connect(&self) -> Result<Box<dyn AsyncWrite + Send + Unpin>> {
+        match &self.output {
+            Output::Socket(path) => {
+                let mut stream = UnixStream::connect(path).await?;
+                stream.write_all(&control(CONTROL_READY)).await?;
+                if read_control(&mut stream).await? != CONTROL_ACCEPT {
+                    return Err(anyhow!("Collector did not accept the content type"));
+                }
+                stream.write_all(&control(CONTROL_START)).await?;
+                Ok(Box::new(stream))
+            }
+            // frames get appended to the stream already in the file
+            // so reconnects and restarts keep the frames written before
+            Output::File(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                if file.metadata().await?.len() == 0 {
+                    file.write_all(&control(CONTROL_START)).await?;
+                }
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    async fn write<W: AsyncWrite + Unpin>(&mut self, writer: W) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        while let Some(frame) = self.receiver.recv().await {
+            writer.write_u32(frame.len() as u32).await?;
+            writer.write_all(&frame).await?;
+
+            // flush as soon as there is nothing left to write
+            while let Ok(frame) = self.receiver.try_recv() {
+                writer.write_u32(frame.len() as u32).await?;
+                writer.write_all(&frame).await?;
+            }
+            writer.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    // frames sent while there is no connection get dropped
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn spawn(mut self) -> Result<()> {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let writer = match self.connect().await {
+                Ok(writer) => writer,
+                Err(e) => {
+                    error!(
+                        "Could not open dnstap output {}, retrying in {:?}",
+                        e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                    continue;
+                }
+            };
+            info!("Writing dnstap frames");
+            backoff = MIN_BACKOFF;
+
+            match self.write(writer).await {
+                Ok(()) => return Ok(()),
+                Err(e) => error!("Could not write dnstap frame {}", e),
+            }
+        }
+    }
+}
+
+pub(super) fn new(config: Dnstap) -> (DnstapLogger, DnstapWriter) {
+    let (sender, receiver) = mpsc::channel(CAPACITY);
+    let output = match (config.socket, config.file) {
+        (Some(socket), _) => Output::Socket(socket),
+        // validated with the config
+        (None, file) => Output::File(file.unwrap_or_default()),
+    };
+
+    let logger = DnstapLogger {
+        sender,
+        identity: config.identity,
+    };
+    (logger, DnstapWriter { output, receiver })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+    use trust_dns_server::authority::{MessageRequest, MessageResponseBuilder};
+    use trust_dns_server::proto::op::{Message, OpCode, Query, ResponseCode};
+    use trust_dns_server::proto::rr::{Name, RecordType};
+    use trust_dns_server::proto::serialize::binary::BinDecodable;
+    use trust_dns_server::server::ResponseHandler;
+
+    use super::{control, read_control, Protobuf, CONTROL_ACCEPT, CONTROL_READY, CONTROL_START};
+    use crate::config::Dnstap;
+    use crate::dns::handler::Transport;
+    use crate::dns::response::ResponseEncoder;
+    use crate::dns::tsig::tests::TestResponseHandler;
+
+    #[test]
+    fn protobuf_varint() {
+        let mut protobuf = Protobuf(Vec::new());
+        protobuf.uint(1, 300);
+        protobuf.bytes(2, b"ab");
+        assert_eq!(vec![0x08, 0xac, 0x02, 0x12, 0x02, b'a', b'b'], protobuf.0);
+    }
+
+    fn query() -> Vec<u8> {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("acme.example.com.").unwrap(),
+            RecordType::TXT,
+        ));
+        message.to_vec().unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    // a restart continues the frame stream instead of replacing it
+    #[tokio::test]
+    async fn appends_frames_to_file() {
+        let path = std::env::temp_dir().join(format!("dnstap-{}.fstrm", std::process::id()));
+        let start = control(CONTROL_START);
+        std::fs::write(&path, &start).unwrap();
+
+        let (logger, writer) = super::new(Dnstap {
+            socket: None,
+            file: Some(path.clone()),
+            identity: None,
+            udp: true,
+            tcp: true,
+        });
+        tokio::spawn(writer.spawn());
+
+        let src = SocketAddr::from_str("10.0.0.1:5353").unwrap();
+        logger.query(&query(), src, Transport::Udp);
+        let mut content = Vec::new();
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            content = std::fs::read(&path).unwrap();
+            if content.len() > start.len() {
+                break;
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(content.starts_with(&start));
+        assert!(!contains(&content[start.len()..], &start));
+        assert!(contains(&content, b"\x04acme\x07example\x03com\x00"));
+    }
+
+    #[tokio::test]
+    async fn writes_frames_to_socket() {
+        let dir = std::env::temp_dir().join(format!("dnstap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (logger, writer) = super::new(Dnstap {
+            socket: Some(path.clone()),
+            file: None,
+            identity: Some("test".to_owned()),
+            udp: true,
+            tcp: true,
+        });
+        tokio::spawn(writer.spawn());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(CONTROL_READY, read_control(&mut stream).await.unwrap());
+        stream.write_all(&control(CONTROL_ACCEPT)).await.unwrap();
+        assert_eq!(CONTROL_START, read_control(&mut stream).await.unwrap());
+
+        let src = SocketAddr::from_str("10.0.0.1:5353").unwrap();
+        let raw = query();
+        let query_time = logger.query(&raw, src, Transport::Udp);
+        let sink = logger.wrap(
+            TestResponseHandler::default(),
+            src,
+            Transport::Udp,
+            query_time,
+        );
+        let mut response_handle = ResponseEncoder::new(sink, None);
+        let request = MessageRequest::from_bytes(&raw).unwrap();
+        let response = MessageResponseBuilder::new(Some(request.raw_queries())).error_msg(
+            request.id(),
+            OpCode::Query,
+            ResponseCode::Refused,
+        );
+        response_handle.send_response(response).unwrap();
+
+        for _ in 0..2 {
+            let length = stream.read_u32().await.unwrap();
+            let mut frame = vec![0; length as usize];
+            stream.read_exact(&mut frame).await.unwrap();
+            assert!(contains(&frame, b"test"));
+            assert!(contains(&frame, &[10, 0, 0, 1]));
+            assert!(contains(&frame, b"\x04acme\x07example\x03com\x00"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
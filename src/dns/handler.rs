@@ -1,79 +1,649 @@
 use futures_util::future::{Join, Map, Ready};
 use futures_util::{future, FutureExt};
-use lazy_static::lazy_static;
-use prometheus::{register_histogram_vec, HistogramTimer, HistogramVec};
 use std::future::Future;
+use std::sync::Arc;
 use tracing::field::Empty;
 use tracing::instrument::Instrumented;
-use tracing::{info_span, Instrument, Span};
-use trust_dns_server::authority::Catalog;
-use trust_dns_server::client::op::LowerQuery;
+use tracing::{error, info, info_span, Instrument, Span};
+use trust_dns_server::authority::{Catalog, MessageResponseBuilder};
+use trust_dns_server::proto::op::{OpCode, ResponseCode};
+use trust_dns_server::proto::rr::RecordType;
 use trust_dns_server::server::{Request, RequestHandler, ResponseHandler};
 
-lazy_static! {
-    static ref DNS_REQ_HISTOGRAM: HistogramVec = register_histogram_vec!(
-        "dns_request_duration_seconds",
-        "The DNS request latencies in seconds.",
-        &["name"]
-    )
-    .unwrap();
-}
+use super::dnstap::DnstapLogger;
+use super::metrics::{self, NameClass, NameClassifier, RequestMetrics};
+use super::response::{ResponseEncoder, ResponseSink};
+use super::rrl::{RateLimiter, RrlSink};
+use super::transfer::transfer;
+use super::tsig::{SignedRequest, TsigError, TsigSigner};
+use super::update::Updater;
+use super::view::Views;
+use crate::config::{Transfer, TsigKeys, Update};
+use crate::facade::DomainFacade;
 
 type ResponseFuture = <Catalog as RequestHandler>::ResponseFuture;
 type ResponseFutureOutput = <ResponseFuture as Future>::Output;
 
-type EndTimer = fn((ResponseFutureOutput, HistogramTimer)) -> ResponseFutureOutput;
+type EndTimer = fn((ResponseFutureOutput, RequestMetrics)) -> ResponseFutureOutput;
 
-type JoinedFuture = Join<ResponseFuture, Ready<HistogramTimer>>;
+type JoinedFuture = Join<ResponseFuture, Ready<RequestMetrics>>;
 type MappedFuture = Instrumented<Map<JoinedFuture, EndTimer>>;
 
-pub(super) struct TraceRequestHandler {
-    catalog: Catalog,
-    span: Span,
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub(super) fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+// decides which requests are allowed to transfer the zone
+pub(super) struct Acl {
+    keys: TsigKeys,
+    transfer: Option<Transfer>,
+    update: Option<Update>,
 }
 
-impl TraceRequestHandler {
-    pub(super) fn new(catalog: Catalog, span: Span) -> Self {
-        TraceRequestHandler { catalog, span }
+impl Acl {
+    pub(super) fn new(keys: TsigKeys, transfer: Option<Transfer>, update: Option<Update>) -> Self {
+        Acl {
+            keys,
+            transfer,
+            update,
+        }
+    }
+
+    // updates are signed with keys from the database, so the updater verifies them
+    fn authorize_update(&self, request: &Request) -> Result<(), ResponseCode> {
+        let update = match &self.update {
+            Some(update) => update,
+            None => {
+                info!("Rejected update, updates are not enabled");
+                return Err(ResponseCode::Refused);
+            }
+        };
+
+        let ip = request.src.ip();
+        if !update.allow.is_empty() && !update.allow.iter().any(|net| net.contains(&ip)) {
+            info!("Rejected update from {}", ip);
+            return Err(ResponseCode::Refused);
+        }
+
+        Ok(())
+    }
+
+    // tsig errors are answered unsigned with notauth
+    fn verify(
+        &self,
+        request: &Request,
+        raw: &[u8],
+        transport: Transport,
+    ) -> Result<Option<TsigSigner>, ResponseCode> {
+        let signed = match SignedRequest::from_request(&request.message, raw) {
+            Ok(Some(signed)) => signed,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                if let TsigError::FormErr = e {
+                    metrics::malformed_tsig(transport);
+                }
+                info!("Rejected request: {}", e);
+                return Err(e.response_code());
+            }
+        };
+
+        let key = match self.keys.get(signed.key_name()) {
+            Some(key) => key,
+            None => {
+                info!("Rejected request: unknown TSIG key {}", signed.key_name());
+                return Err(ResponseCode::NotAuth);
+            }
+        };
+
+        match signed.verify(key) {
+            Ok(signer) => Ok(Some(signer)),
+            Err(e) => {
+                info!("Rejected request: {}", e);
+                Err(e.response_code())
+            }
+        }
+    }
+
+    fn authorize(
+        &self,
+        request: &Request,
+        raw: &[u8],
+        transport: Transport,
+    ) -> Result<Option<TsigSigner>, ResponseCode> {
+        let signer = self.verify(request, raw, transport)?;
+
+        if !is_transfer(request) {
+            return Ok(signer);
+        }
+
+        let transfer = match &self.transfer {
+            Some(transfer) if transport == Transport::Tcp => transfer,
+            Some(_) => {
+                info!("Rejected transfer over udp");
+                return Err(ResponseCode::Refused);
+            }
+            None => return Err(ResponseCode::Refused),
+        };
+
+        let ip = request.src.ip();
+        if !transfer.allow.is_empty() && !transfer.allow.iter().any(|net| net.contains(&ip)) {
+            info!("Rejected transfer from {}", ip);
+            return Err(ResponseCode::Refused);
+        }
+
+        if transfer.keys.is_empty() {
+            return Ok(signer);
+        }
+        match signer {
+            Some(signer) if transfer.keys.contains(signer.key_name()) => Ok(Some(signer)),
+            _ => {
+                info!("Rejected transfer without valid TSIG key");
+                Err(ResponseCode::Refused)
+            }
+        }
     }
 }
 
-impl RequestHandler for TraceRequestHandler {
-    type ResponseFuture = MappedFuture;
+fn is_transfer(request: &Request) -> bool {
+    request
+        .message
+        .queries()
+        .iter()
+        .any(|query| matches!(query.query_type(), RecordType::AXFR | RecordType::IXFR))
+}
+
+pub(super) struct TraceRequestHandler<F> {
+    views: Arc<Views>,
+    updater: Updater<F>,
+    span: Span,
+    transport: Transport,
+    acl: Arc<Acl>,
+    classifier: Arc<NameClassifier>,
+    rrl: Option<Arc<RateLimiter>>,
+    dnstap: Option<DnstapLogger>,
+}
+
+impl<F> TraceRequestHandler<F> {
+    pub(super) fn new(
+        views: Arc<Views>,
+        updater: Updater<F>,
+        span: Span,
+        transport: Transport,
+        acl: Arc<Acl>,
+        classifier: Arc<NameClassifier>,
+    ) -> Self {
+        TraceRequestHandler {
+            views,
+            updater,
+            span,
+            transport,
+            acl,
+            classifier,
+            rrl: None,
+            dnstap: None,
+        }
+    }
+
+    pub(super) fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    // only makes sense for udp as tcp sources cannot be spoofed
+    pub(super) fn with_rrl(mut self, rrl: Option<Arc<RateLimiter>>) -> Self {
+        self.rrl = rrl;
+        self
+    }
 
-    fn handle_request<R: ResponseHandler>(
+    pub(super) fn with_dnstap(mut self, dnstap: Option<DnstapLogger>) -> Self {
+        self.dnstap = dnstap;
+        self
+    }
+}
+
+impl<F> TraceRequestHandler<F>
+where
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    fn dispatch<S: ResponseSink>(
         &self,
         request: Request,
-        response_handle: R,
-    ) -> Self::ResponseFuture {
-        let name = request
+        raw: &[u8],
+        response_handle: ResponseEncoder<S>,
+    ) -> ResponseFuture {
+        if request.message.op_code() == OpCode::Update {
+            if let Err(response_code) = self.acl.authorize_update(&request) {
+                return reject(&request, response_handle, response_code);
+            }
+            let update = self
+                .updater
+                .clone()
+                .update(request, raw.to_vec(), response_handle);
+            return Box::pin(update);
+        }
+
+        let (view, catalog) = self.views.select(request.src.ip());
+        Span::current().record("view", &view);
+
+        let response_handle = match self.acl.authorize(&request, raw, self.transport) {
+            Ok(Some(signer)) => response_handle.with_signer(signer),
+            Ok(None) => response_handle,
+            Err(response_code) => return reject(&request, response_handle, response_code),
+        };
+
+        if !is_transfer(&request) {
+            return catalog.handle_request(request, response_handle);
+        }
+        let authority = request
             .message
             .queries()
             .first()
-            .map(LowerQuery::name)
-            .map(ToString::to_string);
+            .and_then(|query| catalog.find(query.name()))
+            .map(|authority| authority.box_clone());
+        match authority {
+            Some(authority) => Box::pin(transfer(authority, request, response_handle)),
+            None => reject(&request, response_handle, ResponseCode::Refused),
+        }
+    }
+}
 
-        let name = name.as_ref().map(|name| [name.as_str()]);
+fn reject<R: ResponseHandler>(
+    request: &Request,
+    mut response_handle: R,
+    response_code: ResponseCode,
+) -> ResponseFuture {
+    let message = &request.message;
+    let response = MessageResponseBuilder::new(Some(message.raw_queries())).error_msg(
+        message.id(),
+        message.op_code(),
+        response_code,
+    );
+    if let Err(e) = response_handle.send_response(response) {
+        error!("Could not send response: {}", e);
+    }
 
-        let name = match &name {
-            Some(name) => &name[..],
-            None => &[],
-        };
+    Box::pin(future::ready(()))
+}
+
+impl<F> TraceRequestHandler<F>
+where
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    // raw are the bytes the request was decoded from, tsig is verified over them
+    pub(super) fn handle_request<S: ResponseSink>(
+        &self,
+        request: Request,
+        raw: &[u8],
+        sink: S,
+    ) -> MappedFuture {
+        metrics::malformed(&request.message, self.transport);
+        let query = request.message.queries().first();
+        let name_class = query
+            .map(|query| self.classifier.classify(query.name()))
+            .unwrap_or(NameClass::Empty);
+        let query_type = query.map(|query| query.query_type());
 
         let addr = request.src;
-        let span = info_span!(parent: &self.span, "request", remote.addr = %addr, name = Empty, query_type = Empty);
+        let span = info_span!(parent: &self.span, "request", remote.addr = %addr, name = Empty, query_type = Empty, view = Empty);
 
-        let timer = DNS_REQ_HISTOGRAM.with_label_values(name).start_timer();
-        let handle_request = self.catalog.handle_request(request, response_handle);
+        let max_payload = match self.transport {
+            Transport::Udp => Some(request.message.max_payload()),
+            Transport::Tcp => None,
+        };
 
-        future::join(handle_request, future::ready(timer))
+        let metrics = RequestMetrics::new(query_type, self.transport, name_class);
+        let sink = metrics.wrap(sink);
+        let handle_request = span.in_scope(|| match &self.dnstap {
+            Some(dnstap) => {
+                let query_time = dnstap.query(raw, addr, self.transport);
+                let sink = dnstap.wrap(sink, addr, self.transport, query_time);
+                let sink = RrlSink::new(sink, self.rrl.clone(), addr.ip());
+                let response_handle = ResponseEncoder::new(sink, max_payload);
+                self.dispatch(request, raw, response_handle)
+            }
+            None => {
+                let sink = RrlSink::new(sink, self.rrl.clone(), addr.ip());
+                let response_handle = ResponseEncoder::new(sink, max_payload);
+                self.dispatch(request, raw, response_handle)
+            }
+        });
+
+        future::join(handle_request, future::ready(metrics))
             .map(end_timer as EndTimer)
             .instrument(span)
     }
 }
 
-fn end_timer((res, timer): (ResponseFutureOutput, HistogramTimer)) -> ResponseFutureOutput {
-    timer.observe_duration();
+fn end_timer((res, metrics): (ResponseFutureOutput, RequestMetrics)) -> ResponseFutureOutput {
+    metrics.observe();
 
     res
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use tracing::Span;
+    use trust_dns_server::authority::MessageRequest;
+    use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+    use trust_dns_server::proto::rr::{Name, RData, Record, RecordSet, RecordType};
+    use trust_dns_server::proto::serialize::binary::BinDecodable;
+    use trust_dns_server::server::Request;
+
+    use super::{Acl, NameClassifier, RateLimiter, TraceRequestHandler, Transport, Updater, Views};
+    use crate::config::{PreconfiguredRecords, Rrl, Transfer, TsigKeys, Update};
+    use crate::dns::tsig::tests::{
+        assert_chained, assert_signed, key, key_name, request_mac, sign, TestResponseHandler,
+    };
+    use crate::dns::{DatabaseAuthority, View};
+    use crate::facade::{Domain, DomainFacade, InMemoryFacade};
+    use crate::util::now;
+
+    pub(crate) fn handler(transport: Transport) -> TraceRequestHandler<InMemoryFacade> {
+        handler_with(transport, InMemoryFacade::default())
+    }
+
+    fn handler_with(
+        transport: Transport,
+        facade: InMemoryFacade,
+    ) -> TraceRequestHandler<InMemoryFacade> {
+        let authority = DatabaseAuthority::new(
+            facade.clone(),
+            "acme.example.com",
+            Default::default(),
+            true,
+            None,
+        );
+        let views = Arc::new(Views::new(authority.as_ref(), &[]));
+
+        let mut keys = TsigKeys::new();
+        keys.insert(key_name(), key());
+        let transfer = Transfer {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            keys: vec![key_name()],
+            notify: vec![],
+        };
+        let update = Update {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let acl = Arc::new(Acl::new(keys, Some(transfer), Some(update)));
+
+        let origin = Name::from_str("acme.example.com.").unwrap();
+        let classifier = Arc::new(NameClassifier::new(origin.clone().into(), &[]));
+        let updater = Updater::new(origin, facade);
+
+        TraceRequestHandler::new(views, updater, Span::none(), transport, acl, classifier)
+    }
+
+    fn axfr() -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(
+                Name::from_str("acme.example.com.").unwrap(),
+                RecordType::AXFR,
+            ));
+        message
+    }
+
+    async fn send(transport: Transport, src: &str, bytes: &[u8]) -> Message {
+        let request = Request {
+            message: MessageRequest::from_bytes(bytes).unwrap(),
+            src: SocketAddr::from_str(src).unwrap(),
+        };
+
+        let response_handler = TestResponseHandler::default();
+        handler(transport)
+            .handle_request(request, bytes, response_handler.clone())
+            .await;
+
+        let response = response_handler.0.lock().pop().unwrap();
+        Message::from_vec(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn transfer_works() {
+        let request = sign(&axfr(), &key_name(), &key(), now());
+        let response = send(Transport::Tcp, "10.0.0.1:5353", &request).await;
+
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        let types = response
+            .answers()
+            .iter()
+            .map(|record| record.record_type())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![RecordType::SOA, RecordType::SOA], types);
+
+        let tsig = response.additionals().last().unwrap();
+        assert_eq!(RecordType::Unknown(250), tsig.record_type());
+        assert_eq!(&key_name(), tsig.name());
+    }
+
+    #[tokio::test]
+    async fn big_transfer_is_split_into_chained_messages() {
+        let facade = InMemoryFacade::default();
+        for i in 0..5000 {
+            let domain = Domain {
+                id: format!("registration-{}", i),
+                username: format!("user-{}", i),
+                password: "password".to_owned(),
+                txt: vec!["a".repeat(43)],
+            };
+            facade.create_domain(&domain).await.unwrap();
+        }
+
+        let request = sign(&axfr(), &key_name(), &key(), now());
+        let src = SocketAddr::from_str("10.0.0.1:5353").unwrap();
+        let response_handler = TestResponseHandler::default();
+        handler_with(Transport::Tcp, facade)
+            .handle_request(
+                Request {
+                    message: MessageRequest::from_bytes(&request).unwrap(),
+                    src,
+                },
+                &request,
+                response_handler.clone(),
+            )
+            .await;
+
+        let responses = response_handler.0.lock().clone();
+        assert!(responses.len() > 1);
+
+        for r in &responses {
+            let m = MessageRequest::from_bytes(r).unwrap();
+            eprintln!(
+                "DBG {} {:?} {:?}",
+                r.len(),
+                m.answers().len(),
+                m.additionals()
+            );
+        }
+        let mut prior_mac = assert_signed(&responses[0], &request_mac(&request));
+        for response in &responses[1..] {
+            prior_mac = assert_chained(response, &prior_mac);
+        }
+
+        let mut answers = vec![];
+        for (i, response) in responses.iter().enumerate() {
+            assert!(response.len() <= usize::from(u16::MAX));
+            let response = Message::from_vec(response).unwrap();
+            assert_eq!(ResponseCode::NoError, response.response_code());
+            assert!(!response.truncated());
+            assert_eq!(i == 0, !response.queries().is_empty());
+            answers.extend(response.answers().iter().map(|record| record.record_type()));
+        }
+        // every registration is served under its id and its challenge name
+        assert_eq!(10002, answers.len());
+        assert_eq!(Some(&RecordType::SOA), answers.first());
+        assert_eq!(Some(&RecordType::SOA), answers.last());
+        assert!(answers[1..10001]
+            .iter()
+            .all(|record_type| *record_type == RecordType::TXT));
+    }
+
+    #[tokio::test]
+    async fn transfer_over_udp_is_refused() {
+        let request = sign(&axfr(), &key_name(), &key(), now());
+        let response = send(Transport::Udp, "10.0.0.1:5353", &request).await;
+
+        assert_eq!(ResponseCode::Refused, response.response_code());
+    }
+
+    #[tokio::test]
+    async fn transfer_from_unknown_network_is_refused() {
+        let request = sign(&axfr(), &key_name(), &key(), now());
+        let response = send(Transport::Tcp, "192.168.0.1:5353", &request).await;
+
+        assert_eq!(ResponseCode::Refused, response.response_code());
+    }
+
+    #[tokio::test]
+    async fn unsigned_transfer_is_refused() {
+        let request = axfr().to_vec().unwrap();
+        let response = send(Transport::Tcp, "10.0.0.1:5353", &request).await;
+
+        assert_eq!(ResponseCode::Refused, response.response_code());
+    }
+
+    #[tokio::test]
+    async fn wrong_signature_is_not_authorized() {
+        let mut key = key();
+        key.secret = b"wrong".to_vec();
+        let request = sign(&axfr(), &key_name(), &key, now());
+        let response = send(Transport::Tcp, "10.0.0.1:5353", &request).await;
+
+        assert_eq!(ResponseCode::NotAuth, response.response_code());
+        assert!(response.additionals().is_empty());
+    }
+
+    #[tokio::test]
+    async fn updates_are_checked_by_acl() {
+        let mut message = Message::new();
+        message
+            .set_id(1)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update)
+            .add_query(Query::query(
+                Name::from_str("acme.example.com.").unwrap(),
+                RecordType::SOA,
+            ));
+        let request = sign(&message, &key_name(), &key(), now());
+
+        let response = send(Transport::Udp, "192.168.0.1:5353", &request).await;
+        assert_eq!(ResponseCode::Refused, response.response_code());
+
+        // the updater does not know the key as it is not stored for a domain
+        let response = send(Transport::Udp, "10.0.0.1:5353", &request).await;
+        assert_eq!(ResponseCode::NotAuth, response.response_code());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_responses_are_slipped() {
+        let rrl = RateLimiter::new(Rrl {
+            responses_per_second: 1,
+            slip: 1,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        });
+        let handler = handler(Transport::Udp).with_rrl(Some(Arc::new(rrl)));
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("acme.example.com.").unwrap(),
+            RecordType::SOA,
+        ));
+        let bytes = message.to_vec().unwrap();
+
+        let response_handler = TestResponseHandler::default();
+        for _ in 0..2 {
+            let request = Request {
+                message: MessageRequest::from_bytes(&bytes).unwrap(),
+                src: SocketAddr::from_str("10.0.0.1:5353").unwrap(),
+            };
+            handler
+                .handle_request(request, &bytes, response_handler.clone())
+                .await;
+        }
+
+        let responses = response_handler.0.lock().clone();
+        let first = Message::from_vec(&responses[0]).unwrap();
+        assert!(!first.truncated());
+        assert_eq!(1, first.answers().len());
+
+        let second = Message::from_vec(&responses[1]).unwrap();
+        assert!(second.truncated());
+        assert!(second.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn view_is_selected_by_source() {
+        let authority = |ip: &str| {
+            let name = Name::from_str("api.acme.example.com.").unwrap();
+            let record = Record::from_rdata(name.clone(), 100, RData::A(ip.parse().unwrap()));
+            let mut records = PreconfiguredRecords::new();
+            records
+                .entry(name)
+                .or_default()
+                .insert(RecordType::A, Arc::new(RecordSet::from(record)));
+            let authority = DatabaseAuthority::new(
+                InMemoryFacade::default(),
+                "acme.example.com",
+                records.clone(),
+                false,
+                None,
+            );
+            (records, authority)
+        };
+        let (_, public) = authority("192.0.2.1");
+        let (records, internal) = authority("10.0.0.2");
+        let internal = View::new(
+            "internal".to_owned(),
+            vec!["10.0.0.0/8".parse().unwrap()],
+            &records,
+            internal,
+        );
+
+        let mut handler = handler(Transport::Udp);
+        handler.views = Arc::new(Views::new(public.as_ref(), &[internal]));
+
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("api.acme.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let bytes = message.to_vec().unwrap();
+
+        for (src, expected) in &[
+            ("10.0.0.1:5353", "10.0.0.2"),
+            ("192.0.2.7:5353", "192.0.2.1"),
+        ] {
+            let request = Request {
+                message: MessageRequest::from_bytes(&bytes).unwrap(),
+                src: SocketAddr::from_str(src).unwrap(),
+            };
+            let response_handler = TestResponseHandler::default();
+            handler
+                .handle_request(request, &bytes, response_handler.clone())
+                .await;
+
+            let response = response_handler.0.lock().pop().unwrap();
+            let response = Message::from_vec(&response).unwrap();
+            let answers = response.answers();
+            assert_eq!(1, answers.len(), "{}", src);
+            assert_eq!(&RData::A(expected.parse().unwrap()), answers[0].rdata());
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::client::rr::LowerName;
+use trust_dns_server::proto::op::{MessageType, ResponseCode};
+use trust_dns_server::proto::rr::{Name, RecordType};
+
+use super::handler::Transport;
+use super::response::ResponseSink;
+
+lazy_static! {
+    static ref DNS_REQ_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "dns_request_duration_seconds",
+        "The DNS request latencies in seconds.",
+        &["query_type", "rcode", "transport", "name_class"]
+    )
+    .unwrap();
+    pub(super) static ref DNS_MALFORMED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dns_malformed_counter",
+        "Sum of malformed DNS requests",
+        &["transport", "reason"]
+    )
+    .unwrap();
+}
+
+// query names are grouped so random subdomains cannot blow up the label cardinality
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum NameClass {
+    Static,
+    AcmeChallenge,
+    Registration,
+    OutOfZone,
+    Empty,
+}
+
+impl NameClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NameClass::Static => "static",
+            NameClass::AcmeChallenge => "acme_challenge",
+            NameClass::Registration => "registration",
+            NameClass::OutOfZone => "out_of_zone",
+            NameClass::Empty => "empty",
+        }
+    }
+}
+
+pub(super) struct NameClassifier {
+    origin: LowerName,
+    records: HashSet<LowerName>,
+}
+
+impl NameClassifier {
+    pub(super) fn new<'a>(origin: LowerName, names: impl IntoIterator<Item = &'a Name>) -> Self {
+        let records = names.into_iter().map(LowerName::from).collect();
+        NameClassifier { origin, records }
+    }
+
+    // same order as the lookups in the authority
+    pub(super) fn classify(&self, name: &LowerName) -> NameClass {
+        if self.records.contains(name) {
+            return NameClass::Static;
+        }
+        if !self.origin.zone_of(name) {
+            return NameClass::OutOfZone;
+        }
+
+        match Name::from(name).iter().next() {
+            Some(b"_acme-challenge") => NameClass::AcmeChallenge,
+            Some(_) => NameClass::Registration,
+            None => NameClass::Empty,
+        }
+    }
+}
+
+// requests which can be decoded but make no sense
+pub(super) fn malformed(message: &MessageRequest, transport: Transport) {
+    let reason = if message.message_type() != MessageType::Query {
+        "response"
+    } else if message.queries().len() != 1 {
+        "query_count"
+    } else {
+        return;
+    };
+
+    DNS_MALFORMED_COUNTER
+        .with_label_values(&[transport.as_str(), reason])
+        .inc();
+}
+
+pub(super) fn malformed_decode(transport: Transport) {
+    DNS_MALFORMED_COUNTER
+        .with_label_values(&[transport.as_str(), "decode"])
+        .inc();
+}
+
+pub(super) fn malformed_tsig(transport: Transport) {
+    DNS_MALFORMED_COUNTER
+        .with_label_values(&[transport.as_str(), "tsig"])
+        .inc();
+}
+
+// the response code is only known once the response gets sent
+pub(super) struct RequestMetrics {
+    start: Instant,
+    query_type: &'static str,
+    transport: Transport,
+    name_class: NameClass,
+    response_code: Arc<Mutex<Option<ResponseCode>>>,
+}
+
+impl RequestMetrics {
+    pub(super) fn new(
+        query_type: Option<RecordType>,
+        transport: Transport,
+        name_class: NameClass,
+    ) -> Self {
+        RequestMetrics {
+            start: Instant::now(),
+            query_type: query_type.map(Into::into).unwrap_or("NONE"),
+            transport,
+            name_class,
+            response_code: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub(super) fn wrap<S: ResponseSink>(&self, sink: S) -> MetricsSink<S> {
+        MetricsSink {
+            sink,
+            response_code: Arc::clone(&self.response_code),
+        }
+    }
+
+    pub(super) fn observe(self) {
+        // requests dropped by rate limiting never get a response
+        let response_code = match *self.response_code.lock() {
+            Some(response_code) => format!("{:?}", response_code),
+            None => "None".to_owned(),
+        };
+
+        DNS_REQ_HISTOGRAM
+            .with_label_values(&[
+                self.query_type,
+                &response_code,
+                self.transport.as_str(),
+                self.name_class.as_str(),
+            ])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct MetricsSink<S> {
+    sink: S,
+    response_code: Arc<Mutex<Option<ResponseCode>>>,
+}
+
+impl<S: ResponseSink> ResponseSink for MetricsSink<S> {
+    fn send(&mut self, response: Vec<u8>) -> io::Result<()> {
+        // the response code is in the low bits of the fourth header byte
+        // extended response codes live in edns and are not needed for metrics
+        if let Some(flags) = response.get(3) {
+            *self.response_code.lock() = Some(ResponseCode::from(0, flags & 0x0f));
+        }
+
+        self.sink.send(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use trust_dns_server::authority::MessageResponseBuilder;
+    use trust_dns_server::client::rr::LowerName;
+    use trust_dns_server::proto::op::{OpCode, ResponseCode};
+    use trust_dns_server::proto::rr::{Name, RecordType};
+    use trust_dns_server::server::ResponseHandler;
+
+    use super::{NameClass, NameClassifier, RequestMetrics, DNS_REQ_HISTOGRAM};
+    use crate::config::PreconfiguredRecords;
+    use crate::dns::handler::Transport;
+    use crate::dns::response::ResponseEncoder;
+    use crate::dns::tsig::tests::TestResponseHandler;
+
+    fn name(name: &str) -> LowerName {
+        LowerName::from(Name::from_str(name).unwrap())
+    }
+
+    #[test]
+    fn classify_names() {
+        let mut records = PreconfiguredRecords::new();
+        for record in &["acme.example.com.", "www.acme.example.com.", "example.org."] {
+            records.insert(Name::from_str(record).unwrap(), HashMap::new());
+        }
+        let classifier = NameClassifier::new(name("acme.example.com."), records.keys());
+
+        let cases = [
+            ("WWW.acme.example.com.", NameClass::Static),
+            ("example.org.", NameClass::Static),
+            (
+                "_acme-challenge.acme.example.com.",
+                NameClass::AcmeChallenge,
+            ),
+            (
+                "_acme-challenge.www.acme.example.com.",
+                NameClass::AcmeChallenge,
+            ),
+            ("random.acme.example.com.", NameClass::Registration),
+            ("random.example.com.", NameClass::OutOfZone),
+        ];
+        for (actual, expected) in cases.iter() {
+            assert_eq!(*expected, classifier.classify(&name(actual)), "{}", actual);
+        }
+    }
+
+    #[test]
+    fn observe_response_code() {
+        let labels = ["CAA", "Refused", "tcp", "out_of_zone"];
+        let count = DNS_REQ_HISTOGRAM
+            .with_label_values(&labels)
+            .get_sample_count();
+
+        let metrics =
+            RequestMetrics::new(Some(RecordType::CAA), Transport::Tcp, NameClass::OutOfZone);
+        let sink = metrics.wrap(TestResponseHandler::default());
+        let mut response_handle = ResponseEncoder::new(sink, None);
+        let response =
+            MessageResponseBuilder::new(None).error_msg(1, OpCode::Query, ResponseCode::Refused);
+        response_handle.send_response(response).unwrap();
+        metrics.observe();
+
+        let actual = DNS_REQ_HISTOGRAM
+            .with_label_values(&labels)
+            .get_sample_count();
+        assert_eq!(count + 1, actual);
+    }
+}
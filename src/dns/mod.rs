@@ -1,45 +1,149 @@
 use anyhow::Result;
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use futures_util::future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, ToSocketAddrs, UdpSocket};
 use tracing::field::{debug, Empty};
-use tracing::{info_span, Span};
-use trust_dns_server::authority::{AuthorityObject, Catalog};
+use tracing::{info_span, Instrument, Span};
+use trust_dns_server::authority::AuthorityObject;
 use trust_dns_server::proto::rr::Name;
-use trust_dns_server::ServerFuture;
+
+use crate::config::{Dnstap, PreconfiguredRecords, Rrl, Transfer, TsigKeys, Update};
+use crate::facade::DomainFacade;
 
 mod authority;
+mod cache;
+mod dnstap;
 mod handler;
+mod metrics;
+mod notify;
+mod response;
+mod rrl;
+mod server;
+mod transfer;
+mod tsig;
+mod update;
+mod view;
 
 pub use authority::DatabaseAuthority;
-use handler::TraceRequestHandler;
+use dnstap::DnstapWriter;
+use handler::{Acl, TraceRequestHandler, Transport};
+use metrics::NameClassifier;
+use notify::Notifier;
+use rrl::RateLimiter;
+use update::Updater;
+pub use view::View;
+use view::Views;
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub struct Dns<A> {
-    server: ServerFuture<TraceRequestHandler>,
+pub struct Dns<A, F>
+where
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    udp: Arc<TraceRequestHandler<F>>,
+    tcp: Arc<TraceRequestHandler<F>>,
+    notifier: Notifier<F>,
+    dnstap: Option<DnstapWriter>,
     addr: A,
     span: Span,
 }
 
 // span setup here makes no sense
 // todo: fix this
-impl<A: ToSocketAddrs> Dns<A> {
-    pub fn new(addr: A, authority: Box<dyn AuthorityObject>) -> Self {
+impl<A, F> Dns<A, F>
+where
+    A: ToSocketAddrs,
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        addr: A,
+        authority: Box<dyn AuthorityObject>,
+        views: Vec<View>,
+        records: &PreconfiguredRecords,
+        facade: F,
+        keys: TsigKeys,
+        transfer: Option<Transfer>,
+        update: Option<Update>,
+        rrl: Option<Rrl>,
+        dnstap: Option<Dnstap>,
+    ) -> Self {
         let span = info_span!("DNS::spawn", local.addr = Empty);
+        let origin = Name::from(authority.origin());
+        let secondaries = transfer
+            .as_ref()
+            .map(|transfer| transfer.notify.clone())
+            .unwrap_or_default();
+        let notifier = Notifier::new(origin.clone(), secondaries, facade.clone());
+        let acl = Arc::new(Acl::new(keys, transfer, update));
+        let updater = Updater::new(origin, facade);
+        let rrl = rrl.map(RateLimiter::new).map(Arc::new);
+        let (udp_dnstap, tcp_dnstap) = match &dnstap {
+            Some(dnstap) => (dnstap.udp, dnstap.tcp),
+            None => (false, false),
+        };
+        let (dnstap, dnstap_writer) = match dnstap.map(dnstap::new) {
+            Some((logger, writer)) => (Some(logger), Some(writer)),
+            None => (None, None),
+        };
+        let names = records.keys().chain(views.iter().flat_map(View::names));
+        let classifier = Arc::new(NameClassifier::new(authority.origin(), names));
+        let views = Arc::new(Views::new(authority.as_ref(), &views));
 
-        let mut catalog = Catalog::new();
-        catalog.upsert(Name::root().into(), authority);
-        let request_handler = TraceRequestHandler::new(catalog, span.clone());
+        // every transport gets its own handler so it knows where requests come from
+        let handler = |transport| {
+            TraceRequestHandler::new(
+                Arc::clone(&views),
+                updater.clone(),
+                span.clone(),
+                transport,
+                Arc::clone(&acl),
+                Arc::clone(&classifier),
+            )
+        };
+        let udp = handler(Transport::Udp)
+            .with_rrl(rrl)
+            .with_dnstap(dnstap.clone().filter(|_| udp_dnstap));
+        let tcp = handler(Transport::Tcp).with_dnstap(dnstap.filter(|_| tcp_dnstap));
+        let udp = Arc::new(udp);
+        let tcp = Arc::new(tcp);
 
-        let server = ServerFuture::new(request_handler);
-
-        Dns { server, addr, span }
+        Dns {
+            udp,
+            tcp,
+            notifier,
+            dnstap: dnstap_writer,
+            addr,
+            span,
+        }
     }
 
     #[tracing::instrument(skip(self))]
-    pub async fn spawn(mut self) -> Result<()> {
+    pub async fn spawn(self) -> Result<()> {
         let udp = UdpSocket::bind(self.addr).await?;
-        self.span.record("local.addr", &debug(udp.local_addr()));
-        self.server.register_socket(udp);
+        let local_addr = udp.local_addr()?;
+        self.span.record("local.addr", &debug(local_addr));
+
+        // zone transfers only work over tcp
+        let tcp = TcpListener::bind(local_addr).await?;
 
-        tokio::spawn(self.server.block_until_done()).await??;
+        let udp = tokio::spawn(server::serve_udp(udp, self.udp));
+        let tcp = tokio::spawn(server::serve_tcp(tcp, self.tcp, TCP_TIMEOUT));
+        let notifier = tokio::spawn(self.notifier.spawn().in_current_span());
+        let dnstap = self.dnstap.map(|dnstap| dnstap.spawn().in_current_span());
+        let dnstap = tokio::spawn(async move {
+            match dnstap {
+                Some(dnstap) => dnstap.await,
+                None => future::pending().await,
+            }
+        });
+        tokio::select! {
+            res = udp => res??,
+            res = tcp => res??,
+            res = notifier => res??,
+            res = dnstap => res??,
+        }
 
         Ok(())
     }
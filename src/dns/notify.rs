@@ -0,0 +1,301 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::Result as IoResult;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time;
+use tracing::{debug, error, info, info_span, Instrument};
+use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_server::proto::rr::{Name, RecordType};
+
+use super::authority::soa_record;
+use crate::facade::DomainFacade;
+
+lazy_static! {
+    static ref DNS_NOTIFY_SENT_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dns_notify_sent_counter",
+        "Sum of sent DNS NOTIFY messages",
+        &["secondary"]
+    )
+    .unwrap();
+    static ref DNS_NOTIFY_FAILED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dns_notify_failed_counter",
+        "Sum of DNS NOTIFY messages which were never acknowledged",
+        &["secondary"]
+    )
+    .unwrap();
+}
+
+const RETRIES: u32 = 5;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// tells secondaries about zone changes as described in RFC 1996
+pub(super) struct Notifier<F> {
+    origin: Name,
+    secondaries: Vec<SocketAddr>,
+    facade: F,
+}
+
+impl<F: DomainFacade> Notifier<F> {
+    pub(super) fn new(origin: Name, secondaries: Vec<SocketAddr>, facade: F) -> Self {
+        Notifier {
+            origin,
+            secondaries,
+            facade,
+        }
+    }
+
+    // only serials which moved get announced, creations, echoes of our own updates
+    // and resyncs after a reconnect change no txt value
+    pub(super) async fn spawn(self) -> Result<()> {
+        let mut changes = self.facade.subscribe_domains();
+        let mut notified = self.facade.zone_serial().await.ok();
+        loop {
+            match changes.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            }
+            // a single notify covers all changes received so far
+            while !matches!(
+                changes.try_recv(),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed)
+            ) {}
+
+            let serial = match self.facade.zone_serial().await {
+                Ok(serial) => serial,
+                Err(e) => {
+                    error!("Could not get zone serial {}", e);
+                    continue;
+                }
+            };
+            if notified == Some(serial) {
+                debug!(serial, "Zone serial did not change");
+                continue;
+            }
+            notified = Some(serial);
+
+            for secondary in &self.secondaries {
+                let span = info_span!("notify", secondary = %secondary, serial);
+                let notify = notify(self.origin.clone(), serial, *secondary);
+                tokio::spawn(notify.instrument(span));
+            }
+        }
+    }
+}
+
+fn message(id: u16, origin: Name, serial: u32) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Notify)
+        .set_authoritative(true)
+        .add_query(Query::query(origin.clone(), RecordType::SOA))
+        .add_answer(soa_record(origin, serial));
+
+    message
+}
+
+fn random_id() -> u16 {
+    let mut id = [0; 2];
+    // the id only guards against stray responses so a fixed one is fine if this fails
+    let _ = SystemRandom::new().fill(&mut id);
+    u16::from_be_bytes(id)
+}
+
+async fn receive_ack(socket: &UdpSocket, secondary: SocketAddr, id: u16) -> IoResult<ResponseCode> {
+    let mut buffer = [0; 512];
+    loop {
+        let (len, src) = socket.recv_from(&mut buffer).await?;
+        if src != secondary {
+            continue;
+        }
+
+        let response = match Message::from_vec(&buffer[..len]) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Invalid response {}", e);
+                continue;
+            }
+        };
+
+        if response.id() == id
+            && response.message_type() == MessageType::Response
+            && response.op_code() == OpCode::Notify
+        {
+            return Ok(response.response_code());
+        }
+    }
+}
+
+// every retry waits twice as long as the one before
+async fn send(secondary: SocketAddr, message: &Message) -> Result<ResponseCode> {
+    let bind = match secondary {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    let bytes = message.to_vec()?;
+    let label = secondary.to_string();
+
+    let mut timeout = TIMEOUT;
+    for _ in 0..RETRIES {
+        DNS_NOTIFY_SENT_COUNTER.with_label_values(&[&label]).inc();
+        socket.send_to(&bytes, secondary).await?;
+
+        match time::timeout(timeout, receive_ack(&socket, secondary, message.id())).await {
+            Ok(res) => return Ok(res?),
+            Err(_) => debug!("Notify timed out after {:?}", timeout),
+        }
+        timeout *= 2;
+    }
+
+    Err(anyhow::anyhow!("Notify was not acknowledged"))
+}
+
+async fn notify(origin: Name, serial: u32, secondary: SocketAddr) {
+    let message = message(random_id(), origin, serial);
+
+    match send(secondary, &message).await {
+        Ok(ResponseCode::NoError) => info!("Notify acknowledged"),
+        Ok(response_code) => {
+            error!("Notify answered with {}", response_code);
+            DNS_NOTIFY_FAILED_COUNTER
+                .with_label_values(&[&secondary.to_string()])
+                .inc();
+        }
+        Err(e) => {
+            error!("{}", e);
+            DNS_NOTIFY_FAILED_COUNTER
+                .with_label_values(&[&secondary.to_string()])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use trust_dns_server::proto::op::{Message, MessageType, OpCode, ResponseCode};
+    use trust_dns_server::proto::rr::{Name, RData, RecordType};
+
+    use super::{message, send, Notifier, DNS_NOTIFY_SENT_COUNTER};
+    use crate::facade::{Domain, DomainFacade, InMemoryFacade};
+
+    fn origin() -> Name {
+        Name::from_str("acme.example.com.").unwrap()
+    }
+
+    async fn secondary() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    // answers the nth notify it receives and returns all received messages
+    async fn answer(
+        secondary: &UdpSocket,
+        nth: usize,
+        response_code: ResponseCode,
+    ) -> Vec<Message> {
+        let mut buffer = [0; 512];
+        let mut received = vec![];
+        loop {
+            let (len, src) = secondary.recv_from(&mut buffer).await.unwrap();
+            let mut message = Message::from_vec(&buffer[..len]).unwrap();
+            received.push(message.clone());
+            if received.len() < nth {
+                continue;
+            }
+
+            message
+                .set_message_type(MessageType::Response)
+                .set_response_code(response_code);
+            let bytes = message.to_vec().unwrap();
+            secondary.send_to(&bytes, src).await.unwrap();
+            return received;
+        }
+    }
+
+    #[test]
+    fn message_contains_soa() {
+        let message = message(42, origin(), 7);
+        assert_eq!(OpCode::Notify, message.op_code());
+        assert!(message.authoritative());
+        assert_eq!(RecordType::SOA, message.queries()[0].query_type());
+        assert_eq!(&origin(), message.queries()[0].name());
+
+        match message.answers()[0].rdata() {
+            RData::SOA(soa) => assert_eq!(7, soa.serial()),
+            _ => panic!("Notify does not contain a soa"),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_works() {
+        let secondary = secondary().await;
+        let addr = secondary.local_addr().unwrap();
+        let message = message(42, origin(), 7);
+
+        let (res, received) = tokio::join!(
+            send(addr, &message),
+            answer(&secondary, 1, ResponseCode::NoError)
+        );
+        assert_eq!(ResponseCode::NoError, res.unwrap());
+        assert_eq!(1, received.len());
+    }
+
+    #[tokio::test]
+    async fn send_retries() {
+        let secondary = secondary().await;
+        let addr = secondary.local_addr().unwrap();
+        let message = message(42, origin(), 7);
+        let sent = DNS_NOTIFY_SENT_COUNTER.with_label_values(&[&addr.to_string()]);
+
+        let (res, received) = tokio::join!(
+            send(addr, &message),
+            answer(&secondary, 2, ResponseCode::Refused)
+        );
+        assert_eq!(ResponseCode::Refused, res.unwrap());
+        assert_eq!(2, received.len());
+        assert_eq!(2, sent.get());
+    }
+
+    #[tokio::test]
+    async fn notifier_sends_on_update() {
+        let secondary = secondary().await;
+        let addr = secondary.local_addr().unwrap();
+
+        let facade = InMemoryFacade::default();
+        let mut domain = Domain {
+            id: "1".to_owned(),
+            username: "1".to_owned(),
+            password: "1".to_owned(),
+            txt: vec![],
+        };
+        facade.create_domain(&domain).await.unwrap();
+
+        let notifier = Notifier::new(origin(), vec![addr], facade.clone());
+        tokio::spawn(notifier.spawn());
+        tokio::task::yield_now().await;
+
+        // creating a domain does not change the serial, a notify for it would arrive first
+        let mut other = domain.clone();
+        other.id = "2".to_owned();
+        facade.create_domain(&other).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        domain.txt = vec!["TXT".to_owned()];
+        facade.update_domain(&domain).await.unwrap();
+
+        let received = answer(&secondary, 1, ResponseCode::NoError).await;
+        match received[0].answers()[0].rdata() {
+            RData::SOA(soa) => assert_eq!(2, soa.serial()),
+            _ => panic!("Notify does not contain a soa"),
+        }
+    }
+}
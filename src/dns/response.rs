@@ -0,0 +1,310 @@
+use std::io::{Error as IoError, Result as IoResult};
+use std::iter;
+use std::sync::Arc;
+use trust_dns_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use trust_dns_server::proto::error::ProtoResult;
+use trust_dns_server::proto::op::{Edns, Header, Query};
+use trust_dns_server::proto::rr::{Record, RecordType};
+use trust_dns_server::proto::serialize::binary::{BinDecodable, BinDecoder, BinEncoder};
+use trust_dns_server::server::ResponseHandler;
+
+use super::tsig::TsigSigner;
+
+// clients announcing a bigger payload still get capped to this
+const MAX_PAYLOAD: u16 = 4096;
+
+// tcp messages are prefixed with a two byte length
+const MAX_TCP_MESSAGE: u16 = u16::MAX;
+
+// two names, the fixed fields of the record and its rdata and a mac of at most 64 bytes
+const MAX_TSIG: u16 = 2 * 255 + 26 + 64;
+
+// room is left for the tsig record and an opt record without options
+const MAX_ANSWERS: u16 = MAX_TCP_MESSAGE - MAX_TSIG - 11;
+
+pub(super) fn encode(response: MessageResponse<'_, '_>) -> ProtoResult<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(512);
+    response.destructive_emit(&mut BinEncoder::new(&mut buffer))?;
+    Ok(buffer)
+}
+
+fn count(message: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([message[offset], message[offset + 1]])
+}
+
+fn set_count(message: &mut [u8], offset: usize, count: u16) {
+    message[offset..offset + 2].copy_from_slice(&count.to_be_bytes());
+}
+
+fn append_tsig(message: &mut Vec<u8>, tsig: &[u8]) {
+    if tsig.is_empty() {
+        return;
+    }
+    let additional_count = count(message, 10);
+    set_count(message, 10, additional_count + 1);
+    message.extend_from_slice(tsig);
+}
+
+// only the question and the opt record are kept so the client retries over tcp
+// and still knows the payload size of the server
+pub(super) fn truncate(message: &[u8]) -> ProtoResult<Vec<u8>> {
+    let mut decoder = BinDecoder::new(message);
+    let header = Header::read(&mut decoder)?;
+    for _ in 0..header.query_count() {
+        Query::read(&mut decoder)?;
+    }
+    let question = decoder.index();
+
+    let records = usize::from(header.answer_count())
+        + usize::from(header.name_server_count())
+        + usize::from(header.additional_count());
+    let mut opt = None;
+    for _ in 0..records {
+        let start = decoder.index();
+        if Record::read(&mut decoder)?.rr_type() == RecordType::OPT {
+            opt = Some(start..decoder.index());
+        }
+    }
+
+    let mut truncated = message[..question].to_vec();
+    // tc flag
+    truncated[2] |= 0x02;
+    set_count(&mut truncated, 6, 0);
+    set_count(&mut truncated, 8, 0);
+    set_count(&mut truncated, 10, 0);
+    if let Some(opt) = opt {
+        truncated.extend_from_slice(&message[opt]);
+        set_count(&mut truncated, 10, 1);
+    }
+    Ok(truncated)
+}
+
+// receives responses once they are encoded
+// so dnstap and metrics don't have to encode them again
+pub(super) trait ResponseSink: Clone + Send + Unpin + 'static {
+    fn send(&mut self, response: Vec<u8>) -> IoResult<()>;
+}
+
+// every response gets encoded here exactly once before it reaches the sink
+// udp responses bigger than the payload size of the client get truncated
+// signed responses get their tsig record after truncation so they stay signed
+#[derive(Clone)]
+pub(super) struct ResponseEncoder<S> {
+    sink: S,
+    max_payload: Option<u16>,
+    signer: Option<Arc<TsigSigner>>,
+}
+
+impl<S> ResponseEncoder<S> {
+    // tcp responses never get truncated
+    pub(super) fn new(sink: S, max_payload: Option<u16>) -> Self {
+        ResponseEncoder {
+            sink,
+            max_payload: max_payload.map(|max_payload| max_payload.min(MAX_PAYLOAD)),
+            signer: None,
+        }
+    }
+
+    pub(super) fn with_signer(mut self, signer: TsigSigner) -> Self {
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+
+    fn sign(&self, message: &[u8]) -> ProtoResult<Vec<u8>> {
+        match &self.signer {
+            Some(signer) => signer.sign(message),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+impl<S: ResponseSink> ResponseEncoder<S> {
+    // answers which do not fit into a single tcp message, like zone transfers, get split
+    // only the first message carries the question, every one is signed on its own
+    pub(super) fn send_answers(
+        &mut self,
+        request: &MessageRequest,
+        header: Header,
+        edns: Option<Edns>,
+        answers: &[&Record],
+    ) -> IoResult<()> {
+        let mut remaining = answers;
+        let mut prior_mac = None;
+        loop {
+            let first = remaining.len() == answers.len();
+            let queries = Some(request.raw_queries()).filter(|_| first);
+            let encode = |answers: &[&Record], max_size| {
+                let mut response = MessageResponseBuilder::new(queries).build(
+                    header,
+                    Box::new(answers.iter().copied()),
+                    Box::new(iter::empty()),
+                    Box::new(iter::empty()),
+                    Box::new(iter::empty()),
+                );
+                if let Some(edns) = &edns {
+                    response.set_edns(edns.clone());
+                }
+
+                let mut message = Vec::with_capacity(512);
+                let mut encoder = BinEncoder::new(&mut message);
+                encoder.set_max_size(max_size);
+                response
+                    .destructive_emit(&mut encoder)
+                    .map_err(IoError::other)?;
+                Ok::<_, IoError>(message)
+            };
+
+            // the encoder stops at the last answer which fits but leaves the bytes of the
+            // answer it rolled back in the buffer, so only the answers which fit are encoded again
+            let mut message = encode(remaining, MAX_ANSWERS)?;
+            let sent = usize::from(count(&message, 6));
+            if sent == 0 && !remaining.is_empty() {
+                return Err(IoError::other("Answer does not fit into a message"));
+            }
+            if sent < remaining.len() {
+                message = encode(&remaining[..sent], MAX_TCP_MESSAGE)?;
+            }
+            remaining = &remaining[sent..];
+
+            if let Some(signer) = &self.signer {
+                let (tsig, mac) = signer
+                    .sign_chained(&message, prior_mac.as_deref())
+                    .map_err(IoError::other)?;
+                append_tsig(&mut message, &tsig);
+                prior_mac = Some(mac);
+            }
+            self.sink.send(message)?;
+
+            if remaining.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S: ResponseSink> ResponseHandler for ResponseEncoder<S> {
+    fn send_response(&mut self, response: MessageResponse<'_, '_>) -> IoResult<()> {
+        let mut message = encode(response).map_err(IoError::other)?;
+        let mut tsig = self.sign(&message).map_err(IoError::other)?;
+
+        let max_payload = self.max_payload.map(usize::from).unwrap_or(usize::MAX);
+        if message.len() + tsig.len() > max_payload {
+            message = truncate(&message).map_err(IoError::other)?;
+            tsig = self.sign(&message).map_err(IoError::other)?;
+        }
+
+        append_tsig(&mut message, &tsig);
+        self.sink.send(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use trust_dns_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+    use trust_dns_server::proto::op::{Edns, Header, Message, MessageType, Query};
+    use trust_dns_server::proto::rr::{Name, RData, Record, RecordType};
+    use trust_dns_server::proto::serialize::binary::BinDecodable;
+    use trust_dns_server::server::ResponseHandler;
+
+    use super::{ResponseEncoder, TsigSigner};
+    use crate::dns::tsig::tests::{assert_signed, signer, TestResponseHandler};
+
+    type Records<'a> = Box<dyn Iterator<Item = &'a Record> + Send + 'a>;
+
+    // the response handler only accepts boxed iterators
+    fn boxed(records: &[Record]) -> Records<'_> {
+        Box::new(records.iter())
+    }
+
+    fn build<'a>(
+        header: Header,
+        message: &'a MessageRequest,
+        additionals: &'a [Record],
+    ) -> MessageResponse<'a, 'a> {
+        MessageResponseBuilder::new(Some(message.raw_queries())).build(
+            header,
+            boxed(message.answers()),
+            boxed(message.name_servers()),
+            boxed(&[]),
+            boxed(additionals),
+        )
+    }
+
+    fn send(records: usize, max_payload: Option<u16>) -> Message {
+        let sent = send_signed(records, max_payload, None);
+        Message::from_vec(&sent).unwrap()
+    }
+
+    fn send_signed(
+        records: usize,
+        max_payload: Option<u16>,
+        signer: Option<TsigSigner>,
+    ) -> Vec<u8> {
+        let name = Name::from_str("acme.example.com.").unwrap();
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .set_edns(Edns::new());
+        for i in 0..records {
+            let ip = Ipv4Addr::from(i as u32);
+            message.add_answer(Record::from_rdata(name.clone(), 100, RData::A(ip)));
+        }
+        let message = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+
+        let mut header = Header::new();
+        header.set_message_type(MessageType::Response);
+        let sent = TestResponseHandler::default();
+        let mut response_handle = ResponseEncoder::new(sent.clone(), max_payload);
+        if let Some(signer) = signer {
+            response_handle = response_handle.with_signer(signer);
+        }
+        // a decoded message keeps edns separate so it gets moved back into the additionals
+        let mut additionals = message.additionals().to_vec();
+        additionals.extend(message.edns().map(Record::from));
+        response_handle
+            .send_response(build(header, &message, &additionals))
+            .unwrap();
+
+        let sent = sent.0.lock().pop().unwrap();
+        sent
+    }
+
+    #[test]
+    fn small_response_is_sent() {
+        let response = send(2, Some(512));
+        assert!(!response.truncated());
+        assert_eq!(2, response.answers().len());
+        assert!(response.edns().is_some());
+    }
+
+    #[test]
+    fn big_response_is_truncated() {
+        let response = send(100, Some(512));
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert_eq!(1, response.queries().len());
+        assert!(response.edns().is_some());
+
+        // tcp has no limit
+        let response = send(100, None);
+        assert!(!response.truncated());
+        assert_eq!(100, response.answers().len());
+    }
+
+    #[test]
+    fn truncated_response_stays_signed() {
+        let (signer, request_mac) = signer();
+        let sent = send_signed(100, Some(512), Some(signer));
+        assert!(sent.len() <= 512);
+        assert_signed(&sent, &request_mac);
+
+        let response = Message::from_vec(&sent).unwrap();
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert!(response.edns().is_some());
+        let tsig = response.additionals().last().unwrap();
+        assert_eq!(RecordType::Unknown(250), tsig.record_type());
+    }
+}
@@ -0,0 +1,379 @@
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error as IoError, Result as IoResult};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+use trust_dns_server::client::rr::LowerName;
+use trust_dns_server::proto::error::ProtoResult;
+use trust_dns_server::proto::op::{Header, Query, ResponseCode};
+use trust_dns_server::proto::rr::{Record, RecordType};
+use trust_dns_server::proto::serialize::binary::{BinDecodable, BinDecoder};
+
+use super::response::{truncate, ResponseSink};
+use crate::config::Rrl;
+
+lazy_static! {
+    static ref DNS_RRL_DROPPED_COUNTER: IntCounter = register_int_counter!(
+        "dns_rrl_dropped_counter",
+        "Sum of DNS responses dropped by response rate limiting"
+    )
+    .unwrap();
+    static ref DNS_RRL_SLIPPED_COUNTER: IntCounter = register_int_counter!(
+        "dns_rrl_slipped_counter",
+        "Sum of truncated DNS responses sent by response rate limiting"
+    )
+    .unwrap();
+}
+
+const WINDOW: Duration = Duration::from_secs(1);
+// the oldest buckets get evicted once the map reaches this size
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, PartialEq)]
+pub(super) enum Action {
+    Send,
+    Slip,
+    Drop,
+}
+
+// identical responses are counted together
+// nxdomain and empty responses are counted per zone like bind does,
+// otherwise random subdomains would each get their own bucket
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct Key {
+    prefix: IpNet,
+    name: Option<LowerName>,
+    query_type: Option<RecordType>,
+}
+
+struct Bucket {
+    responses: u32,
+    limited: u32,
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<Key, Bucket>,
+    // every bucket with its start, the oldest first
+    order: VecDeque<(Key, Instant)>,
+}
+
+impl Buckets {
+    // expired buckets are removed instead of reset, so the order stays sorted
+    fn evict(&mut self, now: Instant) {
+        while let Some((_, start)) = self.order.front() {
+            let expired = now.duration_since(*start) >= WINDOW;
+            if !expired && self.map.len() < MAX_BUCKETS {
+                break;
+            }
+
+            if let Some((key, _)) = self.order.pop_front() {
+                self.map.remove(&key);
+            }
+        }
+    }
+
+    fn get(&mut self, key: Key, now: Instant) -> &mut Bucket {
+        self.evict(now);
+        let order = &mut self.order;
+        self.map.entry(key).or_insert_with_key(|key| {
+            order.push_back((key.clone(), now));
+            Bucket {
+                responses: 0,
+                limited: 0,
+            }
+        })
+    }
+}
+
+pub(super) struct RateLimiter {
+    config: Rrl,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(config: Rrl) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    fn prefix(&self, ip: IpAddr) -> IpNet {
+        let prefix = match ip {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+        // the prefix length gets validated with the config
+        IpNet::new(ip, prefix).unwrap_or_else(|_| ip.into()).trunc()
+    }
+
+    fn key(&self, ip: IpAddr, response: &[u8]) -> ProtoResult<Key> {
+        let mut decoder = BinDecoder::new(response);
+        let header = Header::read(&mut decoder)?;
+        let mut key = Key {
+            prefix: self.prefix(ip),
+            name: None,
+            query_type: None,
+        };
+        for i in 0..header.query_count() {
+            let query = Query::read(&mut decoder)?;
+            if i == 0 {
+                key.name = Some(query.name().into());
+                key.query_type = Some(query.query_type());
+            }
+        }
+
+        let response_code = ResponseCode::from(0, header.response_code());
+        let empty = response_code == ResponseCode::NXDomain
+            || (response_code == ResponseCode::NoError && header.answer_count() == 0);
+        if !empty {
+            return Ok(key);
+        }
+
+        // the soa in the authority section names the zone
+        for _ in 0..header.answer_count() {
+            Record::read(&mut decoder)?;
+        }
+        for _ in 0..header.name_server_count() {
+            let record = Record::read(&mut decoder)?;
+            if record.rr_type() == RecordType::SOA {
+                key.name = Some(record.name().into());
+                key.query_type = None;
+                break;
+            }
+        }
+        Ok(key)
+    }
+
+    // is_multiple_of would need rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub(super) fn check(&self, ip: IpAddr, response: &[u8], now: Instant) -> Action {
+        let key = match self.key(ip, response) {
+            Ok(key) => key,
+            Err(e) => {
+                debug!("Could not classify response: {}", e);
+                return Action::Send;
+            }
+        };
+
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.get(key, now);
+        if bucket.responses < self.config.responses_per_second {
+            bucket.responses += 1;
+            return Action::Send;
+        }
+
+        bucket.limited = bucket.limited.wrapping_add(1);
+        let slip = self.config.slip;
+        if slip > 0 && bucket.limited % slip == 0 {
+            DNS_RRL_SLIPPED_COUNTER.inc();
+            Action::Slip
+        } else {
+            DNS_RRL_DROPPED_COUNTER.inc();
+            Action::Drop
+        }
+    }
+}
+
+// limits encoded responses, so they are classified by what actually gets sent
+// only makes sense for udp as tcp sources cannot be spoofed
+#[derive(Clone)]
+pub(super) struct RrlSink<S> {
+    sink: S,
+    limiter: Option<Arc<RateLimiter>>,
+    ip: IpAddr,
+}
+
+impl<S> RrlSink<S> {
+    pub(super) fn new(sink: S, limiter: Option<Arc<RateLimiter>>, ip: IpAddr) -> Self {
+        RrlSink { sink, limiter, ip }
+    }
+}
+
+impl<S: ResponseSink> ResponseSink for RrlSink<S> {
+    fn send(&mut self, response: Vec<u8>) -> IoResult<()> {
+        let limiter = match &self.limiter {
+            Some(limiter) => limiter,
+            None => return self.sink.send(response),
+        };
+
+        match limiter.check(self.ip, &response, Instant::now()) {
+            Action::Send => self.sink.send(response),
+            // a truncated response makes real clients retry over tcp
+            Action::Slip => {
+                debug!("Rate limited response slipped");
+                let truncated = truncate(&response).map_err(IoError::other)?;
+                self.sink.send(truncated)
+            }
+            Action::Drop => {
+                debug!("Rate limited response dropped");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use trust_dns_server::proto::op::{Message, MessageType, Query, ResponseCode};
+    use trust_dns_server::proto::rr::rdata::TXT;
+    use trust_dns_server::proto::rr::{Name, RData, Record, RecordType};
+
+    use super::{Action, RateLimiter, DNS_RRL_SLIPPED_COUNTER, MAX_BUCKETS};
+    use crate::config::Rrl;
+    use crate::dns::authority::soa_record;
+
+    fn limiter(slip: u32) -> RateLimiter {
+        RateLimiter::new(Rrl {
+            responses_per_second: 2,
+            slip,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        })
+    }
+
+    fn response(name: &str) -> Vec<u8> {
+        let name = Name::from_str(name).unwrap();
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(name.clone(), RecordType::TXT))
+            .add_answer(Record::from_rdata(
+                name,
+                100,
+                RData::TXT(TXT::new(vec!["proof".to_owned()])),
+            ));
+        message.to_vec().unwrap()
+    }
+
+    fn nxdomain(name: &str) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_response_code(ResponseCode::NXDomain)
+            .add_query(Query::query(Name::from_str(name).unwrap(), RecordType::TXT))
+            .add_name_server(soa_record(Name::from_str("acme.example.com.").unwrap(), 1));
+        message.to_vec().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn limits_identical_responses() {
+        let limiter = limiter(0);
+        let response = response("acme.example.com.");
+        let now = Instant::now();
+
+        assert_eq!(Action::Send, limiter.check(ip("10.0.0.1"), &response, now));
+        assert_eq!(Action::Send, limiter.check(ip("10.0.0.2"), &response, now));
+        assert_eq!(Action::Drop, limiter.check(ip("10.0.0.3"), &response, now));
+
+        // other prefixes and names are counted separately
+        assert_eq!(Action::Send, limiter.check(ip("10.0.1.1"), &response, now));
+        let other = self::response("other.acme.example.com.");
+        assert_eq!(Action::Send, limiter.check(ip("10.0.0.1"), &other, now));
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            Action::Send,
+            limiter.check(ip("10.0.0.1"), &response, later)
+        );
+    }
+
+    #[test]
+    fn ipv6_prefix() {
+        let limiter = limiter(0);
+        let response = response("acme.example.com.");
+        let now = Instant::now();
+
+        assert_eq!(
+            Action::Send,
+            limiter.check(ip("2001:db8::1"), &response, now)
+        );
+        assert_eq!(
+            Action::Send,
+            limiter.check(ip("2001:db8:0:ff::1"), &response, now)
+        );
+        assert_eq!(
+            Action::Drop,
+            limiter.check(ip("2001:db8::2"), &response, now)
+        );
+        assert_eq!(
+            Action::Send,
+            limiter.check(ip("2001:db8:1::1"), &response, now)
+        );
+    }
+
+    #[test]
+    fn nxdomain_is_limited_per_zone() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+
+        let actions = ["a", "b", "c"]
+            .iter()
+            .map(|label| {
+                let response = nxdomain(&format!("{}.acme.example.com.", label));
+                limiter.check(ip("10.0.0.1"), &response, now)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Action::Send, Action::Send, Action::Drop], actions);
+    }
+
+    #[test]
+    fn slips_every_nth_response() {
+        let limiter = limiter(2);
+        let response = response("acme.example.com.");
+        let now = Instant::now();
+        let slipped = DNS_RRL_SLIPPED_COUNTER.get();
+
+        let actions = (0..6)
+            .map(|_| limiter.check(ip("10.0.0.1"), &response, now))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Action::Send,
+                Action::Send,
+                Action::Drop,
+                Action::Slip,
+                Action::Drop,
+                Action::Slip
+            ],
+            actions
+        );
+        assert!(DNS_RRL_SLIPPED_COUNTER.get() >= slipped + 2);
+    }
+
+    #[test]
+    fn oldest_buckets_are_evicted() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        let first = response("acme.example.com.");
+        limiter.check(ip("10.0.0.1"), &first, now);
+        limiter.check(ip("10.0.0.1"), &first, now);
+
+        for i in 0..MAX_BUCKETS {
+            let ip = IpAddr::from(Ipv4Addr::from((i as u32 + 1) << 8));
+            limiter.check(ip, &first, now);
+        }
+        assert_eq!(MAX_BUCKETS, limiter.buckets.lock().map.len());
+        // the bucket of the first network got evicted and starts again
+        assert_eq!(Action::Send, limiter.check(ip("10.0.0.1"), &first, now));
+
+        // expired buckets are removed
+        let later = now + Duration::from_secs(1);
+        limiter.check(ip("10.0.0.1"), &first, later);
+        assert_eq!(1, limiter.buckets.lock().map.len());
+        assert_eq!(1, limiter.buckets.lock().order.len());
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use futures_util::StreamExt;
+use std::io::{Error as IoError, Result as IoResult};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::debug;
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::proto::iocompat::AsyncIoTokioAsStd;
+use trust_dns_server::proto::serialize::binary::BinDecodable;
+use trust_dns_server::proto::tcp::TcpStream;
+use trust_dns_server::proto::udp::UdpStream;
+use trust_dns_server::proto::xfer::SerialMessage;
+use trust_dns_server::proto::BufStreamHandle;
+use trust_dns_server::server::{Request, TimeoutStream};
+
+use super::handler::TraceRequestHandler;
+use super::metrics;
+use super::response::ResponseSink;
+use crate::facade::DomainFacade;
+
+// same as the ServerFuture of trust-dns, which only hands the decoded request to the handler
+// tsig macs have to be verified over the bytes the client sent, so they are kept here
+
+pub(super) async fn serve_udp<F>(
+    socket: UdpSocket,
+    handler: Arc<TraceRequestHandler<F>>,
+) -> Result<()>
+where
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    let (mut stream, stream_handle) = UdpStream::with_bound(socket);
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("Could not receive udp request: {}", e);
+                continue;
+            }
+        };
+
+        let handler = Arc::clone(&handler);
+        let stream_handle = stream_handle.clone();
+        tokio::spawn(async move { handle(message, &handler, stream_handle).await });
+    }
+
+    Err(anyhow!("Unexpected close of udp socket"))
+}
+
+pub(super) async fn serve_tcp<F>(
+    listener: TcpListener,
+    handler: Arc<TraceRequestHandler<F>>,
+    timeout: Duration,
+) -> Result<()>
+where
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    loop {
+        let (tcp_stream, src) = match listener.accept().await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("Could not accept tcp connection: {}", e);
+                continue;
+            }
+        };
+
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            let (stream, stream_handle) =
+                TcpStream::from_stream(AsyncIoTokioAsStd(tcp_stream), src);
+            let mut stream = TimeoutStream::new(stream, timeout);
+
+            // requests of one connection are not spawned so a client cannot take up too many resources
+            while let Some(message) = stream.next().await {
+                match message {
+                    Ok(message) => handle(message, &handler, stream_handle.clone()).await,
+                    Err(e) => {
+                        debug!("Closing tcp connection from {}: {}", src, e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn handle<F>(
+    message: SerialMessage,
+    handler: &TraceRequestHandler<F>,
+    stream_handle: BufStreamHandle,
+) where
+    F: DomainFacade + Clone + Send + Sync + Unpin + 'static,
+{
+    let (raw, src) = message.into_parts();
+    let message = match MessageRequest::from_bytes(&raw) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("Could not decode request from {}: {}", src, e);
+            metrics::malformed_decode(handler.transport());
+            return;
+        }
+    };
+
+    let request = Request { message, src };
+    let sink = StreamSink { src, stream_handle };
+    handler.handle_request(request, &raw, sink).await
+}
+
+// sends encoded responses back to where the request came from
+#[derive(Clone)]
+struct StreamSink {
+    src: SocketAddr,
+    stream_handle: BufStreamHandle,
+}
+
+impl ResponseSink for StreamSink {
+    fn send(&mut self, response: Vec<u8>) -> IoResult<()> {
+        self.stream_handle
+            .send(SerialMessage::new(response, self.src))
+            .map_err(|_| IoError::other("Connection closed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use trust_dns_server::proto::op::{Message, Query, ResponseCode};
+    use trust_dns_server::proto::rr::{Name, RecordType};
+
+    use super::serve_udp;
+    use crate::dns::handler::tests::handler;
+    use crate::dns::handler::Transport;
+    use crate::dns::metrics::DNS_MALFORMED_COUNTER;
+
+    #[tokio::test]
+    async fn udp_request_is_answered() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(serve_udp(socket, Arc::new(handler(Transport::Udp))));
+
+        let mut message = Message::new();
+        message.set_id(42).add_query(Query::query(
+            Name::from_str("acme.example.com.").unwrap(),
+            RecordType::SOA,
+        ));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&message.to_vec().unwrap(), addr)
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 512];
+        let len = client.recv(&mut buffer).await.unwrap();
+        let response = Message::from_vec(&buffer[..len]).unwrap();
+        assert_eq!(42, response.id());
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(1, response.answers().len());
+    }
+
+    #[tokio::test]
+    async fn undecodable_request_is_counted() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(serve_udp(socket, Arc::new(handler(Transport::Udp))));
+        let counter = DNS_MALFORMED_COUNTER.with_label_values(&["udp", "decode"]);
+        let malformed = counter.get();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&[0, 42, 1], addr).await.unwrap();
+
+        // requests get handled in their own task
+        for _ in 0..100 {
+            if counter.get() > malformed {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Undecodable request was not counted");
+    }
+}
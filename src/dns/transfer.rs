@@ -0,0 +1,57 @@
+use tracing::error;
+use trust_dns_server::authority::{AuthorityObject, LookupError};
+use trust_dns_server::proto::op::{Edns, Header, MessageType, OpCode, ResponseCode};
+use trust_dns_server::proto::rr::dnssec::SupportedAlgorithms;
+use trust_dns_server::server::Request;
+
+use super::response::{ResponseEncoder, ResponseSink};
+use super::update::respond;
+
+// the catalog answers with a single message which gets cut off at 64 KiB,
+// so transfers are sent by hand and split into as many messages as needed
+pub(super) async fn transfer<S: ResponseSink>(
+    authority: Box<dyn AuthorityObject>,
+    request: Request,
+    mut response_handle: ResponseEncoder<S>,
+) {
+    let message = &request.message;
+    let query = match message.queries().first() {
+        Some(query) => query,
+        None => return respond(message, response_handle, ResponseCode::FormErr),
+    };
+
+    let lookup = match authority
+        .search(query, false, SupportedAlgorithms::new())
+        .await
+    {
+        Ok(lookup) => lookup,
+        Err(LookupError::ResponseCode(response_code)) => {
+            return respond(message, response_handle, response_code)
+        }
+        Err(e) => {
+            error!("Could not transfer zone: {}", e);
+            return respond(message, response_handle, ResponseCode::ServFail);
+        }
+    };
+
+    let mut header = Header::new();
+    header
+        .set_id(message.id())
+        .set_op_code(OpCode::Query)
+        .set_message_type(MessageType::Response)
+        .set_authoritative(true);
+
+    // same as the catalog announces for other responses
+    let edns = message.edns().map(|edns| {
+        let mut response_edns = Edns::new();
+        response_edns.set_dnssec_ok(true);
+        response_edns.set_max_payload(edns.max_payload().max(512));
+        response_edns.set_version(0);
+        response_edns
+    });
+
+    let answers = lookup.iter().collect::<Vec<_>>();
+    if let Err(e) = response_handle.send_answers(message, header, edns, &answers) {
+        error!("Could not send transfer: {}", e);
+    }
+}
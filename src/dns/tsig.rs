@@ -0,0 +1,596 @@
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use std::fmt::{Display, Formatter};
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::proto::error::ProtoResult;
+use trust_dns_server::proto::op::{Header, Query, ResponseCode};
+use trust_dns_server::proto::rr::rdata::NULL;
+use trust_dns_server::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns_server::proto::serialize::binary::{
+    BinDecodable, BinDecoder, BinEncodable, BinEncoder,
+};
+
+use crate::config::{TsigAlgorithm, TsigKey};
+use crate::util::now;
+
+// trust-dns 0.20 does not know about tsig so it ends up as an unknown record
+const TSIG: RecordType = RecordType::Unknown(250);
+const FUDGE: u16 = 300;
+
+#[derive(Debug, PartialEq)]
+pub(super) enum TsigError {
+    FormErr,
+    BadSig,
+    BadKey,
+    BadTime,
+}
+
+impl TsigError {
+    pub(super) fn response_code(&self) -> ResponseCode {
+        match self {
+            TsigError::FormErr => ResponseCode::FormErr,
+            _ => ResponseCode::NotAuth,
+        }
+    }
+}
+
+impl Display for TsigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let error = match self {
+            TsigError::FormErr => "malformed TSIG record",
+            TsigError::BadSig => "TSIG signature does not match",
+            TsigError::BadKey => "TSIG key is unknown",
+            TsigError::BadTime => "TSIG time is outside of the fudge window",
+        };
+        f.write_str(error)
+    }
+}
+
+fn algorithm(algorithm: TsigAlgorithm) -> hmac::Algorithm {
+    match algorithm {
+        TsigAlgorithm::HmacSha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+        TsigAlgorithm::HmacSha384 => hmac::HMAC_SHA384,
+        TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+    }
+}
+
+// tsig rdata as defined in RFC 8945 section 4.2
+#[derive(Debug, Clone, PartialEq)]
+struct Tsig {
+    algorithm: Name,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    fn read(data: &[u8]) -> ProtoResult<Self> {
+        let mut decoder = BinDecoder::new(data);
+        let algorithm = Name::read(&mut decoder)?;
+        let high = u64::from(decoder.read_u16()?.unverified());
+        let low = u64::from(decoder.read_u32()?.unverified());
+        let fudge = decoder.read_u16()?.unverified();
+        let mac_len = decoder.read_u16()?.unverified();
+        let mac = decoder.read_vec(mac_len as usize)?.unverified();
+        let original_id = decoder.read_u16()?.unverified();
+        let error = decoder.read_u16()?.unverified();
+        let other_len = decoder.read_u16()?.unverified();
+        let other = decoder.read_vec(other_len as usize)?.unverified();
+
+        Ok(Tsig {
+            algorithm,
+            time_signed: high << 32 | low,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    fn emit_time(&self, encoder: &mut BinEncoder<'_>) -> ProtoResult<()> {
+        encoder.emit_u16((self.time_signed >> 32) as u16)?;
+        encoder.emit_u32(self.time_signed as u32)?;
+        encoder.emit_u16(self.fudge)
+    }
+
+    fn to_record(&self, key_name: &Name) -> ProtoResult<Record> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = BinEncoder::new(&mut data);
+            self.algorithm.emit_as_canonical(&mut encoder, true)?;
+            self.emit_time(&mut encoder)?;
+            encoder.emit_u16(self.mac.len() as u16)?;
+            encoder.emit_vec(&self.mac)?;
+            encoder.emit_u16(self.original_id)?;
+            encoder.emit_u16(self.error)?;
+            encoder.emit_u16(self.other.len() as u16)?;
+            encoder.emit_vec(&self.other)?;
+        }
+
+        let rdata = RData::Unknown {
+            code: 250,
+            rdata: NULL::with(data),
+        };
+        let mut record = Record::from_rdata(key_name.clone(), 0, rdata);
+        record.set_dns_class(DNSClass::ANY);
+        Ok(record)
+    }
+
+    // RFC 8945 section 4.3
+    fn mac(
+        &self,
+        key_name: &Name,
+        key: &TsigKey,
+        request_mac: Option<&[u8]>,
+        message: &[u8],
+    ) -> ProtoResult<Vec<u8>> {
+        let mut variables = Vec::new();
+        {
+            let mut encoder = BinEncoder::new(&mut variables);
+            key_name
+                .to_lowercase()
+                .emit_as_canonical(&mut encoder, true)?;
+            DNSClass::ANY.emit(&mut encoder)?;
+            encoder.emit_u32(0)?;
+            self.algorithm
+                .to_lowercase()
+                .emit_as_canonical(&mut encoder, true)?;
+            self.emit_time(&mut encoder)?;
+            encoder.emit_u16(self.error)?;
+            encoder.emit_u16(self.other.len() as u16)?;
+            encoder.emit_vec(&self.other)?;
+        }
+
+        Ok(sign_mac(key, request_mac, message, &variables))
+    }
+
+    // RFC 8945 section 5.3.1, later messages of a transfer only cover the timers
+    // and are chained to the mac of the previous message instead of the request
+    fn chained_mac(&self, key: &TsigKey, prior_mac: &[u8], message: &[u8]) -> ProtoResult<Vec<u8>> {
+        let mut timers = Vec::new();
+        self.emit_time(&mut BinEncoder::new(&mut timers))?;
+
+        Ok(sign_mac(key, Some(prior_mac), message, &timers))
+    }
+}
+
+fn sign_mac(key: &TsigKey, prior_mac: Option<&[u8]>, message: &[u8], variables: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(algorithm(key.algorithm), &key.secret);
+    let mut context = hmac::Context::with_key(&key);
+    if let Some(prior_mac) = prior_mac {
+        context.update(&(prior_mac.len() as u16).to_be_bytes());
+        context.update(prior_mac);
+    }
+    context.update(message);
+    context.update(variables);
+
+    context.sign().as_ref().to_vec()
+}
+
+// RFC 8945 section 4.3.3, the mac covers the message as the client sent it
+// without the tsig record, with the original id and the decremented additional count
+fn unsigned_message(raw: &[u8], original_id: u16) -> ProtoResult<Vec<u8>> {
+    let mut decoder = BinDecoder::new(raw);
+    let header = Header::read(&mut decoder)?;
+    let additional_count = header.additional_count().saturating_sub(1);
+    let records = usize::from(header.answer_count())
+        + usize::from(header.name_server_count())
+        + usize::from(additional_count);
+
+    for _ in 0..header.query_count() {
+        Query::read(&mut decoder)?;
+    }
+    for _ in 0..records {
+        Record::read(&mut decoder)?;
+    }
+
+    let mut message = raw[..decoder.index()].to_vec();
+    message[..2].copy_from_slice(&original_id.to_be_bytes());
+    message[10..12].copy_from_slice(&additional_count.to_be_bytes());
+    Ok(message)
+}
+
+// a request carrying a tsig record which has not been verified yet
+#[derive(Debug)]
+pub(super) struct SignedRequest {
+    key_name: Name,
+    tsig: Tsig,
+    message: Vec<u8>,
+}
+
+impl SignedRequest {
+    // the tsig record has to be the last record of the message
+    // raw are the bytes the request was decoded from
+    pub(super) fn from_request(
+        request: &MessageRequest,
+        raw: &[u8],
+    ) -> Result<Option<Self>, TsigError> {
+        let additionals = request.additionals();
+        let (record, rest) = match additionals.split_last() {
+            Some((record, rest)) if record.rr_type() == TSIG => (record, rest),
+            _ => return Ok(None),
+        };
+
+        if rest.iter().any(|record| record.rr_type() == TSIG) {
+            return Err(TsigError::FormErr);
+        }
+
+        let tsig = match record.rdata() {
+            RData::Unknown { rdata, .. } => rdata.anything().map(Tsig::read),
+            _ => None,
+        };
+
+        let tsig = match tsig {
+            Some(Ok(tsig)) => tsig,
+            _ => return Err(TsigError::FormErr),
+        };
+        let message = unsigned_message(raw, tsig.original_id).map_err(|_| TsigError::FormErr)?;
+
+        Ok(Some(SignedRequest {
+            key_name: record.name().clone(),
+            tsig,
+            message,
+        }))
+    }
+
+    pub(super) fn key_name(&self) -> &Name {
+        &self.key_name
+    }
+
+    pub(super) fn verify(self, key: &TsigKey) -> Result<TsigSigner, TsigError> {
+        let SignedRequest {
+            key_name,
+            tsig,
+            message,
+        } = self;
+
+        let expected_algorithm = Name::from_ascii(key.algorithm.as_str()).unwrap();
+        if !tsig.algorithm.eq(&expected_algorithm) {
+            return Err(TsigError::BadKey);
+        }
+
+        let mac = tsig
+            .mac(&key_name, key, None, &message)
+            .map_err(|_| TsigError::FormErr)?;
+        verify_slices_are_equal(&mac, &tsig.mac).map_err(|_| TsigError::BadSig)?;
+
+        let now = now();
+        if now.max(tsig.time_signed) - now.min(tsig.time_signed) > u64::from(tsig.fudge) {
+            return Err(TsigError::BadTime);
+        }
+
+        Ok(TsigSigner {
+            key_name,
+            key: key.clone(),
+            request: tsig,
+        })
+    }
+}
+
+// signs responses to a verified request
+#[derive(Debug)]
+pub(super) struct TsigSigner {
+    key_name: Name,
+    key: TsigKey,
+    request: Tsig,
+}
+
+impl TsigSigner {
+    pub(super) fn key_name(&self) -> &Name {
+        &self.key_name
+    }
+
+    // the tsig record for an encoded response, the record has to be the last one of the message
+    pub(super) fn sign(&self, message: &[u8]) -> ProtoResult<Vec<u8>> {
+        Ok(self.sign_chained(message, None)?.0)
+    }
+
+    // messages after the first one of a transfer pass the mac of the previous one
+    // returns the tsig record and its mac
+    pub(super) fn sign_chained(
+        &self,
+        message: &[u8],
+        prior_mac: Option<&[u8]>,
+    ) -> ProtoResult<(Vec<u8>, Vec<u8>)> {
+        let mut tsig = Tsig {
+            algorithm: self.request.algorithm.clone(),
+            time_signed: now(),
+            fudge: FUDGE,
+            mac: vec![],
+            original_id: self.request.original_id,
+            error: 0,
+            other: vec![],
+        };
+        // the mac covers the original id, the id in the header could have been changed by a forwarder
+        let mut message = message.to_vec();
+        message[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        tsig.mac = match prior_mac {
+            Some(prior_mac) => tsig.chained_mac(&self.key, prior_mac, &message)?,
+            None => tsig.mac(&self.key_name, &self.key, Some(&self.request.mac), &message)?,
+        };
+
+        let record = tsig.to_record(&self.key_name)?.to_bytes()?;
+        Ok((record, tsig.mac))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use parking_lot::Mutex;
+    use std::io::Result as IoResult;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use trust_dns_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+    use trust_dns_server::proto::op::{Header, Message, MessageType, OpCode, Query};
+    use trust_dns_server::proto::rr::{Name, RecordType};
+    use trust_dns_server::proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
+    use trust_dns_server::server::ResponseHandler;
+
+    use super::{SignedRequest, Tsig, TsigError, FUDGE};
+    use crate::config::{TsigAlgorithm, TsigKey};
+    use crate::dns::response::{ResponseEncoder, ResponseSink};
+    use crate::util::now;
+
+    pub(crate) fn key() -> TsigKey {
+        TsigKey {
+            algorithm: TsigAlgorithm::HmacSha256,
+            secret: b"super secret key".to_vec(),
+        }
+    }
+
+    pub(crate) fn key_name() -> Name {
+        Name::from_str("transfer.").unwrap()
+    }
+
+    // signs a message the same way a client would
+    pub(crate) fn sign(message: &Message, key_name: &Name, key: &TsigKey, time: u64) -> Vec<u8> {
+        let unsigned = message.to_vec().unwrap();
+        let mut tsig = Tsig {
+            algorithm: Name::from_ascii(key.algorithm.as_str()).unwrap(),
+            time_signed: time,
+            fudge: FUDGE,
+            mac: vec![],
+            original_id: message.id(),
+            error: 0,
+            other: vec![],
+        };
+        tsig.mac = tsig.mac(key_name, key, None, &unsigned).unwrap();
+
+        let mut signed = message.clone();
+        signed.add_additional(tsig.to_record(key_name).unwrap());
+        signed.to_vec().unwrap()
+    }
+
+    fn axfr() -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(4242)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(
+                Name::from_str("acme.example.com.").unwrap(),
+                RecordType::AXFR,
+            ));
+        message
+    }
+
+    fn verify(bytes: &[u8], key: &TsigKey) -> Result<super::TsigSigner, TsigError> {
+        let request = MessageRequest::from_bytes(bytes).unwrap();
+        let signed = SignedRequest::from_request(&request, bytes)?.unwrap();
+        assert_eq!(&key_name(), signed.key_name());
+        signed.verify(key)
+    }
+
+    #[test]
+    fn unsigned_request() {
+        let bytes = axfr().to_vec().unwrap();
+        let request = MessageRequest::from_bytes(&bytes).unwrap();
+        assert!(SignedRequest::from_request(&request, &bytes)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn verify_works() {
+        let bytes = sign(&axfr(), &key_name(), &key(), now());
+        let signer = verify(&bytes, &key()).unwrap();
+        assert_eq!(&key_name(), signer.key_name());
+    }
+
+    // appends a tsig record to a message built by hand, the mac is computed over exactly these bytes
+    pub(crate) fn sign_wire(unsigned: &[u8], key_name: &Name, key: &TsigKey, time: u64) -> Vec<u8> {
+        let key_name = key_name.to_bytes().unwrap();
+        let algorithm = Name::from_ascii(key.algorithm.as_str())
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let time = &time.to_be_bytes()[2..];
+
+        // class ANY and ttl 0
+        let mut variables = key_name.clone();
+        variables.extend_from_slice(&[0x00, 0xff, 0, 0, 0, 0]);
+        variables.extend_from_slice(&algorithm);
+        variables.extend_from_slice(time);
+        variables.extend_from_slice(&FUDGE.to_be_bytes());
+        variables.extend_from_slice(&[0, 0, 0, 0]);
+
+        let hmac_key = ring::hmac::Key::new(super::algorithm(key.algorithm), &key.secret);
+        let mac = ring::hmac::sign(&hmac_key, &[unsigned, &variables[..]].concat());
+
+        let mut rdata = algorithm;
+        rdata.extend_from_slice(time);
+        rdata.extend_from_slice(&FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.as_ref().len() as u16).to_be_bytes());
+        rdata.extend_from_slice(mac.as_ref());
+        rdata.extend_from_slice(&unsigned[..2]);
+        rdata.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut signed = unsigned.to_vec();
+        signed[11] += 1;
+        signed.extend_from_slice(&key_name);
+        signed.extend_from_slice(&[0x00, 0xfa, 0x00, 0xff, 0, 0, 0, 0]);
+        signed.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        signed.extend_from_slice(&rdata);
+        signed
+    }
+
+    #[test]
+    fn verify_handcrafted_message() {
+        let mut unsigned = vec![
+            0x10, 0x92, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        unsigned.extend_from_slice(b"\x04acme\x07example\x03com\x00\x00\xfc\x00\x01");
+        let signed = sign_wire(&unsigned, &key_name(), &key(), now());
+
+        verify(&signed, &key()).unwrap();
+    }
+
+    // update like lego sends it, its dns library does not compress names by default
+    // trust-dns would compress the repeated zone name, so only the received bytes match the mac
+    #[test]
+    fn verify_uncompressed_message() {
+        let mut unsigned = vec![
+            0x5c, 0x31, 0x28, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        ];
+        unsigned.extend_from_slice(b"\x07example\x03org\x00\x00\x06\x00\x01");
+        unsigned.extend_from_slice(b"\x0f_acme-challenge\x07example\x03org\x00");
+        unsigned.extend_from_slice(b"\x00\x10\x00\x01\x00\x00\x00\x3c\x00\x06\x05token");
+        let signed = sign_wire(&unsigned, &key_name(), &key(), now());
+
+        let reencoded = Message::from_vec(&signed).unwrap().to_vec().unwrap();
+        assert_ne!(signed, reencoded);
+
+        verify(&signed, &key()).unwrap();
+    }
+
+    // forwarders may change the id, the mac covers the original one
+    #[test]
+    fn verify_forwarded_message() {
+        let mut bytes = sign(&axfr(), &key_name(), &key(), now());
+        bytes[..2].copy_from_slice(&[0xbe, 0xef]);
+
+        verify(&bytes, &key()).unwrap();
+    }
+
+    #[test]
+    fn verify_wrong_key() {
+        let bytes = sign(&axfr(), &key_name(), &key(), now());
+        let mut key = key();
+        key.secret = b"wrong".to_vec();
+
+        assert_eq!(TsigError::BadSig, verify(&bytes, &key).unwrap_err());
+    }
+
+    #[test]
+    fn verify_wrong_algorithm() {
+        let bytes = sign(&axfr(), &key_name(), &key(), now());
+        let mut key = key();
+        key.algorithm = TsigAlgorithm::HmacSha512;
+
+        assert_eq!(TsigError::BadKey, verify(&bytes, &key).unwrap_err());
+    }
+
+    #[test]
+    fn verify_tampered() {
+        let mut bytes = sign(&axfr(), &key_name(), &key(), now());
+        // set the recursion desired flag after signing
+        bytes[2] |= 0x01;
+
+        assert_eq!(TsigError::BadSig, verify(&bytes, &key()).unwrap_err());
+    }
+
+    #[test]
+    fn verify_old_time() {
+        let bytes = sign(&axfr(), &key_name(), &key(), now() - 1000);
+        assert_eq!(TsigError::BadTime, verify(&bytes, &key()).unwrap_err());
+    }
+
+    #[derive(Clone, Default)]
+    pub(crate) struct TestResponseHandler(pub(crate) Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl ResponseHandler for TestResponseHandler {
+        fn send_response(&mut self, response: MessageResponse<'_, '_>) -> IoResult<()> {
+            let mut buffer = Vec::new();
+            response
+                .destructive_emit(&mut BinEncoder::new(&mut buffer))
+                .unwrap();
+            self.0.lock().push(buffer);
+            Ok(())
+        }
+    }
+
+    impl ResponseSink for TestResponseHandler {
+        fn send(&mut self, response: Vec<u8>) -> IoResult<()> {
+            self.0.lock().push(response);
+            Ok(())
+        }
+    }
+
+    // signer for a signed transfer request and the mac of that request
+    pub(crate) fn signer() -> (super::TsigSigner, Vec<u8>) {
+        let request = sign(&axfr(), &key_name(), &key(), now());
+        let signer = verify(&request, &key()).unwrap();
+        let request_mac = signer.request.mac.clone();
+        (signer, request_mac)
+    }
+
+    pub(crate) fn request_mac(request: &[u8]) -> Vec<u8> {
+        verify(request, &key()).unwrap().request.mac
+    }
+
+    // returns the mac so the next message of a transfer can be checked
+    pub(crate) fn assert_signed(response: &[u8], request_mac: &[u8]) -> Vec<u8> {
+        let message = MessageRequest::from_bytes(response).unwrap();
+        let signed = SignedRequest::from_request(&message, response)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&key_name(), signed.key_name());
+
+        let expected = signed
+            .tsig
+            .mac(&key_name(), &key(), Some(request_mac), &signed.message)
+            .unwrap();
+        assert_eq!(expected, signed.tsig.mac);
+        signed.tsig.mac
+    }
+
+    pub(crate) fn assert_chained(response: &[u8], prior_mac: &[u8]) -> Vec<u8> {
+        let message = MessageRequest::from_bytes(response).unwrap();
+        let signed = SignedRequest::from_request(&message, response)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&key_name(), signed.key_name());
+
+        let expected = signed
+            .tsig
+            .chained_mac(&key(), prior_mac, &signed.message)
+            .unwrap();
+        assert_eq!(expected, signed.tsig.mac);
+        signed.tsig.mac
+    }
+
+    #[test]
+    fn response_is_signed() {
+        let (signer, request_mac) = signer();
+        let sent = TestResponseHandler::default();
+        let mut handler = ResponseEncoder::new(sent.clone(), None).with_signer(signer);
+
+        let request = axfr();
+        let mut header = Header::new();
+        header
+            .set_id(request.id())
+            .set_message_type(MessageType::Response);
+        let request = MessageRequest::from_bytes(&request.to_vec().unwrap()).unwrap();
+        let response =
+            MessageResponseBuilder::new(Some(request.raw_queries())).build_no_records(header);
+        handler.send_response(response).unwrap();
+
+        let sent = sent.0.lock().pop().unwrap();
+        assert_signed(&sent, &request_mac);
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet};
+use tracing::{error, info};
+use trust_dns_server::authority::{MessageRequest, MessageResponseBuilder};
+use trust_dns_server::client::rr::LowerName;
+use trust_dns_server::proto::op::ResponseCode;
+use trust_dns_server::proto::rr::rdata::TXT;
+use trust_dns_server::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns_server::server::{Request, ResponseHandler};
+
+use super::response::{ResponseEncoder, ResponseSink};
+use super::tsig::{SignedRequest, TsigSigner};
+use crate::config::TsigKey;
+use crate::facade::{Domain, DomainFacade};
+
+const ACME_CHALLENGE: &[u8] = b"_acme-challenge";
+
+// handles rfc 2136 updates
+// this cannot be done in the authority as its update function is not async
+#[derive(Clone)]
+pub(super) struct Updater<F> {
+    origin: Name,
+    facade: F,
+}
+
+pub(super) fn respond<R: ResponseHandler>(
+    message: &MessageRequest,
+    mut response_handle: R,
+    response_code: ResponseCode,
+) {
+    let response = MessageResponseBuilder::new(Some(message.raw_queries())).error_msg(
+        message.id(),
+        message.op_code(),
+        response_code,
+    );
+    if let Err(e) = response_handle.send_response(response) {
+        error!("Could not send response: {}", e);
+    }
+}
+
+fn txt_value(txt: &TXT) -> Result<String, ResponseCode> {
+    let value = txt.txt_data().concat();
+    String::from_utf8(value).map_err(|_| ResponseCode::FormErr)
+}
+
+// class any and none records of updates carry no data
+fn is_empty(rdata: &RData) -> bool {
+    matches!(rdata, RData::NULL(null) if null.anything().unwrap_or_default().is_empty())
+}
+
+impl<F: DomainFacade + Sync> Updater<F> {
+    pub(super) fn new(origin: Name, facade: F) -> Self {
+        Updater { origin, facade }
+    }
+
+    // raw are the bytes the request was decoded from, the tsig mac covers them
+    pub(super) async fn update<S: ResponseSink>(
+        self,
+        request: Request,
+        raw: Vec<u8>,
+        response_handle: ResponseEncoder<S>,
+    ) {
+        let message = &request.message;
+        let (signer, domain) = match self.verify(message, &raw).await {
+            Ok(res) => res,
+            Err(response_code) => return respond(message, response_handle, response_code),
+        };
+
+        let response_code = match self.apply(message, &domain).await {
+            Ok(()) => ResponseCode::NoError,
+            Err(response_code) => response_code,
+        };
+        respond(message, response_handle.with_signer(signer), response_code)
+    }
+
+    // every domain has its own key, so the key decides which domain gets updated
+    async fn verify(
+        &self,
+        message: &MessageRequest,
+        raw: &[u8],
+    ) -> Result<(TsigSigner, Domain), ResponseCode> {
+        let signed = match SignedRequest::from_request(message, raw) {
+            Ok(Some(signed)) => signed,
+            Ok(None) => {
+                info!("Rejected unsigned update");
+                return Err(ResponseCode::Refused);
+            }
+            Err(e) => {
+                info!("Rejected update: {}", e);
+                return Err(e.response_code());
+            }
+        };
+
+        let name = signed.key_name().to_lowercase().to_ascii();
+        let key = match self
+            .facade
+            .find_domain_key(name.trim_end_matches('.'))
+            .await
+        {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                info!("Rejected update: unknown TSIG key {}", name);
+                return Err(ResponseCode::NotAuth);
+            }
+            Err(e) => {
+                error!("Could not load TSIG key {}", e);
+                return Err(ResponseCode::ServFail);
+            }
+        };
+
+        let algorithm = match key.algorithm.parse() {
+            Ok(algorithm) => algorithm,
+            Err(e) => {
+                error!("{}", e);
+                return Err(ResponseCode::ServFail);
+            }
+        };
+        let tsig_key = TsigKey {
+            algorithm,
+            secret: key.secret,
+        };
+
+        let signer = match signed.verify(&tsig_key) {
+            Ok(signer) => signer,
+            Err(e) => {
+                info!("Rejected update: {}", e);
+                return Err(e.response_code());
+            }
+        };
+
+        match self.facade.find_domain_by_id(&key.domain).await {
+            Ok(Some(domain)) => Ok((signer, domain)),
+            Ok(None) => {
+                error!("Domain not found {}", key.domain);
+                Err(ResponseCode::ServFail)
+            }
+            Err(e) => {
+                error!("{}", e);
+                Err(ResponseCode::ServFail)
+            }
+        }
+    }
+
+    // rfc 2136 section 3.1.1, the origin is the only zone which can be updated
+    fn check_zone(&self, message: &MessageRequest) -> Result<(), ResponseCode> {
+        let zone = match message.queries() {
+            [zone] if zone.query_type() == RecordType::SOA => zone,
+            _ => {
+                info!("Rejected update without a single zone");
+                return Err(ResponseCode::FormErr);
+            }
+        };
+
+        if zone.name() != &LowerName::new(&self.origin) {
+            info!("Rejected update of zone {}", zone.name());
+            return Err(ResponseCode::Refused);
+        }
+
+        Ok(())
+    }
+
+    // only the names of the registration the key belongs to can be updated
+    // both serve the same txt records
+    fn is_domain_name(&self, name: &Name, domain: &Domain) -> bool {
+        let domain_name = match Name::from_ascii(&domain.id) {
+            Ok(id) => id.append_domain(&self.origin),
+            Err(_) => return false,
+        };
+
+        if domain_name.eq(name) {
+            return true;
+        }
+        name.iter().next() == Some(ACME_CHALLENGE) && domain_name.eq(&name.base_name())
+    }
+
+    // rfc 2136 section 3.2, the registration only has txt records
+    // so a name is in use as long as it has one
+    fn check_prerequisites(
+        &self,
+        message: &MessageRequest,
+        domain: &Domain,
+        txt: &[String],
+    ) -> Result<(), ResponseCode> {
+        // values of prerequisites which have to match the whole rrset
+        let mut rrsets: HashMap<LowerName, HashSet<String>> = HashMap::new();
+        for record in message.answers() {
+            if record.ttl() != 0 {
+                return Err(ResponseCode::FormErr);
+            }
+            if !self.is_domain_name(record.name(), domain) {
+                info!("Rejected prerequisite of {}", record.name());
+                return Err(ResponseCode::NotZone);
+            }
+
+            let exists = match record.rr_type() {
+                RecordType::TXT | RecordType::ANY => !txt.is_empty(),
+                _ => false,
+            };
+            let any = record.rr_type() == RecordType::ANY;
+            match record.dns_class() {
+                DNSClass::ANY | DNSClass::NONE if !is_empty(record.rdata()) => {
+                    return Err(ResponseCode::FormErr)
+                }
+                DNSClass::ANY if exists => {}
+                DNSClass::ANY if any => return Err(ResponseCode::NXDomain),
+                DNSClass::ANY => return Err(ResponseCode::NXRRSet),
+                DNSClass::NONE if !exists => {}
+                DNSClass::NONE if any => return Err(ResponseCode::YXDomain),
+                DNSClass::NONE => return Err(ResponseCode::YXRRSet),
+                DNSClass::IN => match record.rdata() {
+                    RData::TXT(value) if record.rr_type() == RecordType::TXT => {
+                        rrsets
+                            .entry(LowerName::new(record.name()))
+                            .or_default()
+                            .insert(txt_value(value)?);
+                    }
+                    _ => return Err(ResponseCode::NXRRSet),
+                },
+                _ => return Err(ResponseCode::FormErr),
+            }
+        }
+
+        let current = txt.iter().cloned().collect::<HashSet<_>>();
+        if rrsets.values().any(|values| values != &current) {
+            return Err(ResponseCode::NXRRSet);
+        }
+
+        Ok(())
+    }
+
+    fn apply_record(
+        &self,
+        record: &Record,
+        domain: &Domain,
+        txt: &mut Vec<String>,
+    ) -> Result<(), ResponseCode> {
+        if !self.is_domain_name(record.name(), domain) {
+            info!("Rejected update of {}", record.name());
+            return Err(ResponseCode::NotZone);
+        }
+
+        // rfc 2136 section 2.5, adding a value twice is ignored
+        match (record.dns_class(), record.rr_type(), record.rdata()) {
+            (DNSClass::IN, RecordType::TXT, RData::TXT(value)) => {
+                let value = txt_value(value)?;
+                if !txt.contains(&value) {
+                    txt.push(value);
+                }
+            }
+            (DNSClass::ANY, RecordType::TXT, _) | (DNSClass::ANY, RecordType::ANY, _) => {
+                txt.clear();
+            }
+            (DNSClass::NONE, RecordType::TXT, RData::TXT(value)) => {
+                let value = txt_value(value)?;
+                txt.retain(|txt| txt != &value);
+            }
+            _ => {
+                info!("Rejected update of {} record", record.rr_type());
+                return Err(ResponseCode::Refused);
+            }
+        }
+
+        Ok(())
+    }
+
+    // prerequisites and updates see the current values, nothing is stored if one fails
+    async fn apply(&self, message: &MessageRequest, domain: &Domain) -> Result<(), ResponseCode> {
+        self.check_zone(message)?;
+
+        let update = |txt: &mut Vec<String>| {
+            self.check_prerequisites(message, domain, txt)?;
+
+            let mut updated = txt.clone();
+            for record in message.name_servers() {
+                self.apply_record(record, domain, &mut updated)?;
+            }
+            *txt = updated;
+            Ok(())
+        };
+
+        match self.facade.update_txt(&domain.id, update).await {
+            Ok(Some(Ok(()))) => {
+                info!("Updated domain {}", domain.id);
+                Ok(())
+            }
+            Ok(Some(Err(response_code))) => Err(response_code),
+            Ok(None) => {
+                error!("Domain not found {}", domain.id);
+                Err(ResponseCode::ServFail)
+            }
+            Err(e) => {
+                error!("{}", e);
+                Err(ResponseCode::ServFail)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use trust_dns_server::authority::MessageRequest;
+    use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+    use trust_dns_server::proto::rr::rdata::TXT;
+    use trust_dns_server::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+    use trust_dns_server::proto::serialize::binary::BinDecodable;
+    use trust_dns_server::server::Request;
+
+    use super::Updater;
+    use crate::config::{TsigAlgorithm, TsigKey};
+    use crate::dns::response::ResponseEncoder;
+    use crate::dns::tsig::tests::{sign, sign_wire, TestResponseHandler};
+    use crate::facade::{Domain, DomainFacade, DomainKey, InMemoryFacade};
+    use crate::util::now;
+
+    const ID: &str = "0e1f8297564a420eb260749d9f5ddd45";
+
+    async fn facade() -> (InMemoryFacade, TsigKey) {
+        let facade = InMemoryFacade::default();
+        let domain = Domain {
+            id: ID.to_owned(),
+            username: ID.to_owned(),
+            password: ID.to_owned(),
+            txt: vec!["old".to_owned()],
+        };
+        facade.create_domain(&domain).await.unwrap();
+
+        let key = DomainKey::new(ID).unwrap();
+        facade.put_domain_key(&key).await.unwrap();
+        let key = TsigKey {
+            algorithm: TsigAlgorithm::HmacSha256,
+            secret: key.secret,
+        };
+
+        (facade, key)
+    }
+
+    fn update_in(zone: &str, prerequisites: Vec<Record>, records: Vec<Record>) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(7)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update)
+            .add_query(Query::query(Name::from_str(zone).unwrap(), RecordType::SOA))
+            .add_answers(prerequisites)
+            .add_name_servers(records);
+        message
+    }
+
+    fn update(records: Vec<Record>) -> Message {
+        update_in("acme.example.com.", vec![], records)
+    }
+
+    fn challenge() -> String {
+        format!("_acme-challenge.{}.acme.example.com.", ID)
+    }
+
+    fn txt(name: &str, class: DNSClass, value: &str) -> Record {
+        let txt = RData::TXT(TXT::new(vec![value.to_owned()]));
+        let mut record = Record::from_rdata(Name::from_str(name).unwrap(), 60, txt);
+        record.set_dns_class(class);
+        record
+    }
+
+    fn delete_all(name: &str) -> Record {
+        empty(name, DNSClass::ANY, RecordType::TXT)
+    }
+
+    fn empty(name: &str, class: DNSClass, rr_type: RecordType) -> Record {
+        let mut record = Record::with(Name::from_str(name).unwrap(), rr_type, 0);
+        record.set_dns_class(class);
+        record
+    }
+
+    async fn send(facade: &InMemoryFacade, bytes: &[u8]) -> Message {
+        let request = Request {
+            message: MessageRequest::from_bytes(bytes).unwrap(),
+            src: SocketAddr::from(([127, 0, 0, 1], 5353)),
+        };
+        let origin = Name::from_str("acme.example.com.").unwrap();
+        let updater = Updater::new(origin, facade.clone());
+
+        let response_handler = TestResponseHandler::default();
+        updater
+            .update(
+                request,
+                bytes.to_vec(),
+                ResponseEncoder::new(response_handler.clone(), None),
+            )
+            .await;
+
+        let response = response_handler.0.lock().pop().unwrap();
+        Message::from_vec(&response).unwrap()
+    }
+
+    async fn txt_of(facade: &InMemoryFacade) -> Vec<String> {
+        facade.find_domain_by_id(ID).await.unwrap().unwrap().txt
+    }
+
+    fn key_name() -> Name {
+        Name::from_str(ID).unwrap()
+    }
+
+    #[tokio::test]
+    async fn add_txt() {
+        let (facade, key) = facade().await;
+        let record = txt(&challenge(), DNSClass::IN, "new");
+        let request = sign(&update(vec![record]), &key_name(), &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(vec!["old", "new"], txt_of(&facade).await);
+        assert_eq!(2, facade.zone_serial().await.unwrap());
+
+        let tsig = response.additionals().last().unwrap();
+        assert_eq!(RecordType::Unknown(250), tsig.record_type());
+    }
+
+    // names are not compressed, the way lego sends updates
+    #[tokio::test]
+    async fn add_txt_uncompressed() {
+        let (facade, key) = facade().await;
+        let mut unsigned = vec![
+            0x00, 0x07, 0x28, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        ];
+        unsigned.extend_from_slice(b"\x04acme\x07example\x03com\x00\x00\x06\x00\x01");
+        unsigned.extend_from_slice(b"\x0f_acme-challenge\x20");
+        unsigned.extend_from_slice(ID.as_bytes());
+        unsigned.extend_from_slice(b"\x04acme\x07example\x03com\x00");
+        unsigned.extend_from_slice(b"\x00\x10\x00\x01\x00\x00\x00\x3c\x00\x04\x03new");
+        let request = sign_wire(&unsigned, &key_name(), &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(vec!["old", "new"], txt_of(&facade).await);
+    }
+
+    #[tokio::test]
+    async fn add_txt_to_domain_name() {
+        let (facade, key) = facade().await;
+        let name = format!("{}.acme.example.com.", ID);
+        let record = txt(&name, DNSClass::IN, "new");
+        let request = sign(&update(vec![record]), &key_name(), &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(vec!["old", "new"], txt_of(&facade).await);
+    }
+
+    #[tokio::test]
+    async fn delete_txt() {
+        let (facade, key) = facade().await;
+
+        let record = txt(&challenge(), DNSClass::NONE, "other");
+        let request = sign(&update(vec![record]), &key_name(), &key, now());
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert_eq!(vec!["old"], txt_of(&facade).await);
+
+        let record = txt(&challenge(), DNSClass::NONE, "old");
+        let request = sign(&update(vec![record]), &key_name(), &key, now());
+        send(&facade, &request).await;
+        assert!(txt_of(&facade).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn delete_all_txt() {
+        let (facade, key) = facade().await;
+        let record = delete_all(&challenge());
+        let request = sign(&update(vec![record]), &key_name(), &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert!(txt_of(&facade).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsigned_update_is_refused() {
+        let (facade, _) = facade().await;
+        let record = txt(&challenge(), DNSClass::IN, "new");
+        let request = update(vec![record]).to_vec().unwrap();
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::Refused, response.response_code());
+        assert_eq!(vec!["old"], txt_of(&facade).await);
+    }
+
+    #[tokio::test]
+    async fn unknown_key_is_not_authorized() {
+        let (facade, key) = facade().await;
+        let record = txt(&challenge(), DNSClass::IN, "new");
+        let key_name = Name::from_str("unknown").unwrap();
+        let request = sign(&update(vec![record]), &key_name, &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NotAuth, response.response_code());
+        assert!(response.additionals().is_empty());
+    }
+
+    #[tokio::test]
+    async fn other_names_are_rejected() {
+        let (facade, key) = facade().await;
+        for name in &[
+            "other.acme.example.com.",
+            "_acme-challenge.other.acme.example.com.",
+            "_acme-challenge.acme.example.com.",
+        ] {
+            let record = txt(name, DNSClass::IN, "new");
+            let request = sign(&update(vec![record]), &key_name(), &key, now());
+
+            let response = send(&facade, &request).await;
+            assert_eq!(ResponseCode::NotZone, response.response_code());
+        }
+        assert_eq!(vec!["old"], txt_of(&facade).await);
+    }
+
+    #[tokio::test]
+    async fn other_zones_are_refused() {
+        let (facade, key) = facade().await;
+        let record = txt("_acme-challenge.example.org.", DNSClass::IN, "new");
+        let message = update_in("example.org.", vec![], vec![record]);
+        let request = sign(&message, &key_name(), &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::Refused, response.response_code());
+        assert_eq!(vec!["old"], txt_of(&facade).await);
+
+        let tsig = response.additionals().last().unwrap();
+        assert_eq!(RecordType::Unknown(250), tsig.record_type());
+    }
+
+    #[tokio::test]
+    async fn prerequisites_are_evaluated() {
+        let (facade, key) = facade().await;
+        let name = challenge();
+        let cases = vec![
+            (
+                empty(&name, DNSClass::ANY, RecordType::TXT),
+                ResponseCode::NoError,
+            ),
+            (
+                empty(&name, DNSClass::ANY, RecordType::ANY),
+                ResponseCode::NoError,
+            ),
+            (
+                empty(&name, DNSClass::ANY, RecordType::A),
+                ResponseCode::NXRRSet,
+            ),
+            (
+                empty(&name, DNSClass::NONE, RecordType::A),
+                ResponseCode::NoError,
+            ),
+            (
+                empty(&name, DNSClass::NONE, RecordType::TXT),
+                ResponseCode::YXRRSet,
+            ),
+            (
+                empty(&name, DNSClass::NONE, RecordType::ANY),
+                ResponseCode::YXDomain,
+            ),
+            (txt(&name, DNSClass::IN, "other"), ResponseCode::NXRRSet),
+        ];
+
+        for (prerequisite, response_code) in cases {
+            let mut prerequisite = prerequisite;
+            prerequisite.set_ttl(0);
+            let record = txt(&name, DNSClass::IN, "new");
+            let message = update_in("acme.example.com.", vec![prerequisite], vec![record]);
+            let request = sign(&message, &key_name(), &key, now());
+
+            let response = send(&facade, &request).await;
+            assert_eq!(response_code, response.response_code());
+            let record = txt(&name, DNSClass::NONE, "new");
+            let request = sign(&update(vec![record]), &key_name(), &key, now());
+            send(&facade, &request).await;
+        }
+
+        // value dependent prerequisites have to match every value
+        let mut prerequisite = txt(&name, DNSClass::IN, "old");
+        prerequisite.set_ttl(0);
+        let record = delete_all(&name);
+        let message = update_in("acme.example.com.", vec![prerequisite], vec![record]);
+        let request = sign(&message, &key_name(), &key, now());
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NoError, response.response_code());
+        assert!(txt_of(&facade).await.is_empty());
+
+        let record = empty(&name, DNSClass::ANY, RecordType::ANY);
+        let message = update_in("acme.example.com.", vec![record], vec![]);
+        let request = sign(&message, &key_name(), &key, now());
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::NXDomain, response.response_code());
+    }
+
+    #[tokio::test]
+    async fn malformed_prerequisites_are_rejected() {
+        let (facade, key) = facade().await;
+        let name = challenge();
+        let other = "_acme-challenge.other.acme.example.com.";
+        let cases = vec![
+            (txt(&name, DNSClass::ANY, "old"), ResponseCode::FormErr),
+            (txt(&name, DNSClass::IN, "old"), ResponseCode::FormErr),
+            (
+                empty(other, DNSClass::ANY, RecordType::TXT),
+                ResponseCode::NotZone,
+            ),
+        ];
+
+        for (ttl, (mut prerequisite, response_code)) in [0, 60, 0].iter().zip(cases) {
+            // class any records carry no data and prerequisites have no ttl
+            prerequisite.set_ttl(*ttl);
+            let record = txt(&name, DNSClass::IN, "new");
+            let message = update_in("acme.example.com.", vec![prerequisite], vec![record]);
+            let request = sign(&message, &key_name(), &key, now());
+
+            let response = send(&facade, &request).await;
+            assert_eq!(response_code, response.response_code());
+        }
+        assert_eq!(vec!["old"], txt_of(&facade).await);
+    }
+
+    #[tokio::test]
+    async fn other_types_are_refused() {
+        let (facade, key) = facade().await;
+        let name = Name::from_str(&challenge()).unwrap();
+        let record = Record::from_rdata(name, 60, RData::A([1, 1, 1, 1].into()));
+        let request = sign(&update(vec![record]), &key_name(), &key, now());
+
+        let response = send(&facade, &request).await;
+        assert_eq!(ResponseCode::Refused, response.response_code());
+        assert_eq!(vec!["old"], txt_of(&facade).await);
+    }
+}
@@ -0,0 +1,133 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+use trust_dns_server::authority::{AuthorityObject, Catalog};
+use trust_dns_server::proto::rr::Name;
+
+use crate::config::PreconfiguredRecords;
+
+const DEFAULT_VIEW: &str = "default";
+
+// a view answers with its own records for clients from its networks
+pub struct View {
+    name: String,
+    networks: Vec<IpNet>,
+    names: Vec<Name>,
+    authority: Box<dyn AuthorityObject>,
+}
+
+impl View {
+    pub fn new(
+        name: String,
+        networks: Vec<IpNet>,
+        records: &PreconfiguredRecords,
+        authority: Box<dyn AuthorityObject>,
+    ) -> Self {
+        View {
+            name,
+            networks,
+            names: records.keys().cloned().collect(),
+            authority,
+        }
+    }
+
+    // names with static records, used to classify requests in metrics
+    pub(super) fn names(&self) -> &[Name] {
+        &self.names
+    }
+}
+
+fn catalog(authority: &dyn AuthorityObject) -> Catalog {
+    let mut catalog = Catalog::new();
+    catalog.upsert(Name::root().into(), authority.box_clone());
+    catalog
+}
+
+struct ViewCatalog {
+    name: String,
+    networks: Vec<IpNet>,
+    catalog: Catalog,
+}
+
+pub(super) struct Views {
+    default: Catalog,
+    views: Vec<ViewCatalog>,
+}
+
+impl Views {
+    pub(super) fn new(authority: &dyn AuthorityObject, views: &[View]) -> Self {
+        let views = views
+            .iter()
+            .map(|view| ViewCatalog {
+                name: view.name.clone(),
+                networks: view.networks.clone(),
+                catalog: catalog(view.authority.as_ref()),
+            })
+            .collect();
+
+        Views {
+            default: catalog(authority),
+            views,
+        }
+    }
+
+    // the view with the most specific matching network wins
+    // a network is only in one view, so there is never a tie
+    pub(super) fn select(&self, ip: IpAddr) -> (&str, &Catalog) {
+        let view = self
+            .views
+            .iter()
+            .filter_map(|view| {
+                let prefix_len = view
+                    .networks
+                    .iter()
+                    .filter(|network| network.contains(&ip))
+                    .map(IpNet::prefix_len)
+                    .max()?;
+                Some((prefix_len, view))
+            })
+            .max_by_key(|(prefix_len, _)| *prefix_len);
+
+        match view {
+            Some((_, view)) => (&view.name, &view.catalog),
+            None => (DEFAULT_VIEW, &self.default),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{View, Views};
+    use crate::dns::DatabaseAuthority;
+    use crate::facade::InMemoryFacade;
+
+    fn view(name: &str, networks: &[&str]) -> View {
+        let networks = networks
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect();
+        let authority = DatabaseAuthority::new(
+            InMemoryFacade::default(),
+            "acme.example.com",
+            Default::default(),
+            false,
+            None,
+        );
+        View::new(name.to_owned(), networks, &Default::default(), authority)
+    }
+
+    #[test]
+    fn select_most_specific_view() {
+        let default = view("default", &[]);
+        let views = [
+            view("internal", &["10.0.0.0/8", "fd00::/8"]),
+            view("office", &["10.1.0.0/16"]),
+        ];
+        let views = Views::new(default.authority.as_ref(), &views);
+
+        let select = |ip: &str| views.select(ip.parse().unwrap()).0;
+        assert_eq!("internal", select("10.0.0.1"));
+        assert_eq!("internal", select("fd00::1"));
+        assert_eq!("office", select("10.1.0.1"));
+        assert_eq!("default", select("192.168.0.1"));
+    }
+}
@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use sqlx::Postgres;
+
+use super::{DatabaseFacade, InMemoryFacade};
+
+// identifies a row of the acme table, realm is a hash of the account contact
+#[derive(Debug, Copy, Clone)]
+pub struct AcmeKey<'a> {
+    pub key: &'a str,
+    pub realm: i64,
+    pub kind: &'a str,
+}
+
+impl AcmeKey<'_> {
+    fn owned(self) -> (String, i64, String) {
+        (self.key.to_owned(), self.realm, self.kind.to_owned())
+    }
+}
+
+#[async_trait]
+pub trait AcmeFacade {
+    async fn find_acme(&self, key: &AcmeKey<'_>) -> Result<Option<Vec<u8>>, sqlx::Error>;
+    // inserts the value or replaces the existing one
+    async fn put_acme(&self, key: &AcmeKey<'_>, value: &[u8]) -> Result<(), sqlx::Error>;
+    // key, realm and value of every row of the kind
+    async fn find_acme_kind(&self, kind: &str) -> Result<Vec<(String, i64, Vec<u8>)>, sqlx::Error>;
+}
+
+#[async_trait]
+impl AcmeFacade for DatabaseFacade<Postgres> {
+    async fn find_acme(&self, key: &AcmeKey<'_>) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT value FROM acme WHERE key = $1 AND realm = $2 AND kind = $3 LIMIT 1",
+        )
+        .bind(key.key)
+        .bind(key.realm)
+        .bind(key.kind)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn put_acme(&self, key: &AcmeKey<'_>, value: &[u8]) -> Result<(), sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        // the table has no unique index so an upsert is not possible
+        let exists: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 from acme WHERE key = $1 AND realm = $2 AND kind = $3)",
+        )
+        .bind(key.key)
+        .bind(key.realm)
+        .bind(key.kind)
+        .fetch_one(&mut transaction)
+        .await?;
+        let query = if exists {
+            "UPDATE acme SET value = $4 WHERE key = $1 AND realm = $2 AND kind = $3"
+        } else {
+            "INSERT INTO acme (key, realm, kind, value) VALUES ($1, $2, $3, $4)"
+        };
+
+        sqlx::query(query)
+            .bind(key.key)
+            .bind(key.realm)
+            .bind(key.kind)
+            .bind(value)
+            .execute(&mut transaction)
+            .await?;
+
+        transaction.commit().await
+    }
+
+    async fn find_acme_kind(&self, kind: &str) -> Result<Vec<(String, i64, Vec<u8>)>, sqlx::Error> {
+        sqlx::query_as("SELECT key, realm, value FROM acme WHERE kind = $1")
+            .bind(kind)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+#[async_trait]
+impl AcmeFacade for InMemoryFacade {
+    async fn find_acme(&self, key: &AcmeKey<'_>) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        let lock = self.0.lock();
+        let value = lock.acme.get(&key.owned()).cloned();
+        Ok(value)
+    }
+
+    async fn put_acme(&self, key: &AcmeKey<'_>, value: &[u8]) -> Result<(), sqlx::Error> {
+        let mut lock = self.0.lock();
+        lock.acme.insert(key.owned(), value.to_vec());
+
+        Ok(())
+    }
+
+    async fn find_acme_kind(&self, kind: &str) -> Result<Vec<(String, i64, Vec<u8>)>, sqlx::Error> {
+        let lock = self.0.lock();
+        let values = lock
+            .acme
+            .iter()
+            .filter(|((_, _, value_kind), _)| value_kind == kind)
+            .map(|((key, realm, _), value)| (key.clone(), *realm, value.clone()))
+            .collect();
+        Ok(values)
+    }
+}
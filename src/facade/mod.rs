@@ -2,39 +2,118 @@ use parking_lot::{Mutex, MutexGuard};
 use sqlx::{Database, PgPool, Pool, Postgres};
 use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::broadcast::{self, Sender};
 
+mod acme;
 pub(crate) mod cert;
 mod domain;
+mod keyring;
+mod listener;
 
+pub use acme::{AcmeFacade, AcmeKey};
 pub use cert::{Cert, CertFacade, State};
-pub use domain::{Domain, DomainDTO, DomainFacade};
+pub use domain::{Domain, DomainChange, DomainDTO, DomainFacade, DomainKey, TsigDTO};
+pub use keyring::Keyring;
+pub(crate) use keyring::{aad, open, seal};
+
+fn crypt_error(error: anyhow::Error) -> sqlx::Error {
+    sqlx::Error::Decode(error.into())
+}
+
+fn no_keyring() -> sqlx::Error {
+    sqlx::Error::Configuration("No encryption key is configured".into())
+}
+
+// changed domains get sent to subscribers
+// if a subscriber lags behind it should assume every domain changed
+const DOMAIN_CHANGES_CAPACITY: usize = 64;
+
+fn domain_changes() -> Sender<DomainChange> {
+    broadcast::channel(DOMAIN_CHANGES_CAPACITY).0
+}
+
+// ids of certs stored by the cert manager, only the latest one matters
+const CERT_CHANGES_CAPACITY: usize = 1;
+
+fn cert_changes() -> Sender<String> {
+    broadcast::channel(CERT_CHANGES_CAPACITY).0
+}
 
 #[derive(Debug)]
 pub struct DatabaseFacade<DB: Database> {
     pool: Pool<DB>,
+    domain_changes: Sender<DomainChange>,
+    cert_changes: Sender<String>,
+    // private keys of certs and secrets of domain keys are encrypted with it if present
+    keyring: Option<Keyring>,
 }
 
 impl<DB: Database> Clone for DatabaseFacade<DB> {
     fn clone(&self) -> Self {
         DatabaseFacade {
             pool: self.pool.clone(),
+            domain_changes: self.domain_changes.clone(),
+            cert_changes: self.cert_changes.clone(),
+            keyring: self.keyring.clone(),
         }
     }
 }
 
+impl<DB: Database> DatabaseFacade<DB> {
+    pub fn with_keyring(mut self, keyring: Option<Keyring>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+}
+
 impl From<PgPool> for DatabaseFacade<Postgres> {
     fn from(pool: PgPool) -> Self {
-        DatabaseFacade { pool }
+        DatabaseFacade {
+            pool,
+            domain_changes: domain_changes(),
+            cert_changes: cert_changes(),
+            keyring: None,
+        }
     }
 }
 
 #[derive(Clone, Default)]
 pub struct InMemoryFacade(Arc<Mutex<InMemoryFacadeInner>>);
 
-#[derive(Clone, Default)]
+impl InMemoryFacade {
+    pub fn with_keyring(self, keyring: Option<Keyring>) -> Self {
+        self.0.lock().keyring = keyring;
+        self
+    }
+}
+
+#[derive(Clone)]
 struct InMemoryFacadeInner {
     certs: HashMap<String, Cert>,
     domains: HashMap<String, Domain>,
+    keys: HashMap<String, DomainKey>,
+    // values of the acme table by key, realm and kind
+    acme: HashMap<(String, i64, String), Vec<u8>>,
+    serial: u32,
+    domain_changes: Sender<DomainChange>,
+    cert_changes: Sender<String>,
+    keyring: Option<Keyring>,
+}
+
+// serial starts at 1 same as the postgres sequence
+impl Default for InMemoryFacadeInner {
+    fn default() -> Self {
+        InMemoryFacadeInner {
+            certs: HashMap::new(),
+            domains: HashMap::new(),
+            keys: HashMap::new(),
+            acme: HashMap::new(),
+            serial: 1,
+            domain_changes: domain_changes(),
+            cert_changes: cert_changes(),
+            keyring: None,
+        }
+    }
 }
 
 type InMemoryFacadeGuard<'a> = MutexGuard<'a, InMemoryFacadeInner>;
@@ -0,0 +1,505 @@
+use anyhow::{anyhow, Result};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use lazy_static::lazy_static;
+use openssl::asn1::{Asn1GeneralizedTimeRef, Asn1Time};
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::X509;
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info};
+
+use crate::cert::unix_time;
+use crate::facade::{Cert, CertFacade};
+use crate::util::{now, to_i64, HOUR_IN_SECONDS};
+
+lazy_static! {
+    static ref OCSP_REFRESH_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ocsp_refresh_counter",
+        "Sum of OCSP response refresh checks",
+        &["result"]
+    )
+    .unwrap();
+    static ref OCSP_NEXT_UPDATE_GAUGE: IntGauge = register_int_gauge!(
+        "ocsp_next_update_timestamp_seconds",
+        "Unix time at which the stapled OCSP response expires"
+    )
+    .unwrap();
+}
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// clock skew accepted between us and the responder
+const MAX_SKEW: u32 = 5 * 60;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Refresh {
+    Refreshed,
+    NotDue,
+    NoCert,
+    // certs without an ocsp url in their authority information access
+    NoResponder,
+}
+
+impl Refresh {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Refresh::Refreshed => "refreshed",
+            Refresh::NotDue => "not_due",
+            Refresh::NoCert => "no_cert",
+            Refresh::NoResponder => "no_responder",
+        }
+    }
+}
+
+// openssl only prints generalized times, like Sep 24 13:55:36 2126 GMT
+fn generalized_time(time: &Asn1GeneralizedTimeRef) -> Result<u64> {
+    let printed = time.to_string();
+    let invalid = || anyhow!("Time {} is invalid", printed);
+
+    let parts = printed.split_whitespace().collect::<Vec<_>>();
+    let (month, day, clock, year) = match parts.as_slice() {
+        [month, day, clock, year, "GMT"] => (month, day, clock, year),
+        _ => return Err(invalid()),
+    };
+    let month = MONTHS.iter().position(|m| m == month).ok_or_else(invalid)? + 1;
+    let day = day.parse::<u32>().map_err(|_| invalid())?;
+    // fractions of seconds get dropped
+    let clock = clock.split('.').next().unwrap_or_default().replace(':', "");
+
+    let time = Asn1Time::from_str(&format!("{}{:02}{:02}{}Z", year, month, day, clock))?;
+    unix_time(&time)
+}
+
+// leaf and issuer of a pem encoded chain, the issuer is needed to identify the leaf
+fn chain(pem: &str) -> Result<(X509, X509)> {
+    let mut chain = X509::stack_from_pem(pem.as_bytes())?.into_iter();
+    match (chain.next(), chain.next()) {
+        (Some(leaf), Some(issuer)) => Ok((leaf, issuer)),
+        _ => Err(anyhow!("Cert chain has no issuer")),
+    }
+}
+
+fn cert_id(leaf: &X509, issuer: &X509) -> Result<OcspCertId> {
+    Ok(OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?)
+}
+
+fn responder(pem: &str) -> Result<Option<String>> {
+    let leaf = X509::from_pem(pem.as_bytes())?;
+    // fails if the cert has no authority information access
+    let responders = match leaf.ocsp_responders() {
+        Ok(responders) => responders,
+        Err(_) => return Ok(None),
+    };
+    let responder = responders.iter().next().map(|url| url.to_string());
+
+    Ok(responder)
+}
+
+// a verified ocsp response which says the cert is good
+#[derive(Debug)]
+pub(crate) struct Staple {
+    this_update: u64,
+    next_update: u64,
+}
+
+impl Staple {
+    // fails for responses which are not signed by the issuer, not for this cert or expired
+    pub(crate) fn parse(pem: &str, der: &[u8]) -> Result<Self> {
+        let (leaf, issuer) = chain(pem)?;
+
+        let response = OcspResponse::from_der(der)?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(anyhow!(
+                "OCSP response status is {}",
+                response.status().as_raw()
+            ));
+        }
+        let basic = response.basic()?;
+
+        // the issuer is trusted on its own as the root may not be part of the chain
+        let mut certs = Stack::new()?;
+        certs.push(issuer.clone())?;
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(issuer.clone())?;
+        store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        basic.verify(&certs, &store.build(), OcspFlag::empty())?;
+
+        let id = cert_id(&leaf, &issuer)?;
+        let status = basic
+            .find_status(&id)
+            .ok_or_else(|| anyhow!("OCSP response does not cover cert"))?;
+        if status.status != OcspCertStatus::GOOD {
+            return Err(anyhow!("OCSP cert status is {}", status.status.as_raw()));
+        }
+        status.check_validity(MAX_SKEW, None)?;
+
+        Ok(Staple {
+            this_update: generalized_time(status.this_update)?,
+            next_update: generalized_time(status.next_update)?,
+        })
+    }
+
+    // responses get refreshed once half of their validity passed
+    fn refresh_time(&self) -> u64 {
+        self.this_update + self.next_update.saturating_sub(self.this_update) / 2
+    }
+}
+
+// counts the result of a refresh check
+fn observe(refresh: &Result<Refresh>) {
+    match refresh {
+        Ok(refresh) => {
+            OCSP_REFRESH_COUNTER
+                .with_label_values(&[refresh.as_str()])
+                .inc();
+            if *refresh == Refresh::Refreshed {
+                info!("Refreshed OCSP response");
+            }
+        }
+        Err(e) => {
+            OCSP_REFRESH_COUNTER.with_label_values(&["failed"]).inc();
+            error!("Could not refresh OCSP response {:#}", e);
+        }
+    }
+}
+
+// the response for the leaf of the chain, checked before it gets stapled
+async fn fetch(client: &Client<HttpConnector>, responder: &str, pem: &str) -> Result<Vec<u8>> {
+    tokio::time::timeout(FETCH_TIMEOUT, request(client, responder, pem))
+        .await
+        .map_err(|_| anyhow!("OCSP responder {} timed out", responder))?
+}
+
+async fn request(client: &Client<HttpConnector>, responder: &str, pem: &str) -> Result<Vec<u8>> {
+    let (leaf, issuer) = chain(pem)?;
+    let mut request = OcspRequest::new()?;
+    request.add_id(cert_id(&leaf, &issuer)?)?;
+
+    let request = Request::post(responder)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Body::from(request.to_der()?))?;
+    let res = client.request(request).await?;
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "OCSP responder {} returned {}",
+            responder,
+            res.status()
+        ));
+    }
+    let der = hyper::body::to_bytes(res.into_body()).await?.to_vec();
+
+    Staple::parse(pem, &der)?;
+    Ok(der)
+}
+
+// fetches ocsp responses for the stored cert and stores them next to it
+// the tls config picks them up like a renewed cert
+pub struct OcspStapler<F> {
+    facade: F,
+    client: Client<HttpConnector>,
+}
+
+impl<F: CertFacade> OcspStapler<F> {
+    pub fn new(facade: F) -> Self {
+        OcspStapler {
+            facade,
+            client: Client::new(),
+        }
+    }
+
+    #[tracing::instrument(name = "OcspStapler::watch", skip(self))]
+    pub async fn watch(self) -> Result<()> {
+        let mut changes = self.facade.subscribe_certs();
+        let mut interval = tokio::time::interval(Duration::from_secs(HOUR_IN_SECONDS));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                // renewed certs need a new response right away
+                res = changes.recv() => {
+                    if let Err(RecvError::Closed) = res {
+                        return Err(anyhow!("Cert changes closed"));
+                    }
+                }
+            }
+
+            observe(&self.manage().await);
+        }
+    }
+
+    async fn manage(&self) -> Result<Refresh> {
+        let cert = match self.facade.first_cert().await? {
+            Some(cert) => cert,
+            None => return Ok(Refresh::NoCert),
+        };
+        let pem = match &cert.cert {
+            Some(pem) => pem,
+            None => return Ok(Refresh::NoCert),
+        };
+
+        let staple = cert.ocsp.as_deref().map(|der| Staple::parse(pem, der));
+        if let Some(Ok(staple)) = &staple {
+            OCSP_NEXT_UPDATE_GAUGE.set(to_i64(&staple.next_update));
+            if staple.refresh_time() > now() {
+                return Ok(Refresh::NotDue);
+            }
+        }
+
+        let responder = match responder(pem)? {
+            Some(responder) => responder,
+            None => return Ok(Refresh::NoResponder),
+        };
+        match self.refresh(&cert, &responder).await {
+            Ok(()) => Ok(Refresh::Refreshed),
+            Err(e) => {
+                // clients reject handshakes with an expired response so none is better
+                if let Some(Err(_)) = staple {
+                    self.facade.update_ocsp(&cert, None).await?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn refresh(&self, cert: &Cert, responder: &str) -> Result<()> {
+        let pem = cert.cert.as_deref().unwrap_or_default();
+        // checked before storing so no replica staples a broken response
+        let der = fetch(&self.client, responder, pem).await?;
+
+        // a renewal in the meantime makes this response useless
+        if self.facade.update_ocsp(cert, Some(&der)).await? {
+            let staple = Staple::parse(pem, &der)?;
+            OCSP_NEXT_UPDATE_GAUGE.set(to_i64(&staple.next_update));
+        }
+
+        Ok(())
+    }
+}
+
+// fetches ocsp responses for certs read from files
+// there is no row to store them in, so every replica keeps its own in memory
+pub(crate) struct FileStapler {
+    client: Client<HttpConnector>,
+}
+
+impl FileStapler {
+    pub(crate) fn new() -> Self {
+        FileStapler {
+            client: Client::new(),
+        }
+    }
+
+    // refreshes the response of every chain, returns true if one of them changed
+    pub(crate) async fn refresh(&self, certs: &[&str], ocsp: &mut [Option<Vec<u8>>]) -> bool {
+        let mut changed = false;
+        for (pem, ocsp) in certs.iter().zip(ocsp) {
+            let current = ocsp.clone();
+            observe(&self.manage(pem, ocsp).await);
+            changed |= *ocsp != current;
+        }
+
+        changed
+    }
+
+    async fn manage(&self, pem: &str, ocsp: &mut Option<Vec<u8>>) -> Result<Refresh> {
+        let staple = ocsp.as_deref().map(|der| Staple::parse(pem, der));
+        if let Some(Ok(staple)) = &staple {
+            if staple.refresh_time() > now() {
+                return Ok(Refresh::NotDue);
+            }
+        }
+
+        let responder = match responder(pem)? {
+            Some(responder) => responder,
+            None => return Ok(Refresh::NoResponder),
+        };
+        match self.refresh_file(pem, &responder, ocsp).await {
+            Ok(()) => Ok(Refresh::Refreshed),
+            Err(e) => {
+                // clients reject handshakes with an expired response so none is better
+                if let Some(Err(_)) = staple {
+                    *ocsp = None;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn refresh_file(
+        &self,
+        pem: &str,
+        responder: &str,
+        ocsp: &mut Option<Vec<u8>>,
+    ) -> Result<()> {
+        *ocsp = Some(fetch(&self.client, responder, pem).await?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use super::{responder, FileStapler, OcspStapler, Refresh, Staple};
+    use crate::facade::{CertFacade, InMemoryFacade};
+
+    const CERT: &str = include_str!("../tests/ocsp.crt");
+    const PRIVATE: &str = include_str!("../tests/ocsp.key");
+    const GOOD: &[u8] = include_bytes!("../tests/ocsp-good.der");
+    const REVOKED: &[u8] = include_bytes!("../tests/ocsp-revoked.der");
+
+    // thisUpdate and nextUpdate of tests/ocsp-good.der
+    const THIS_UPDATE: u64 = 1792331736;
+    const NEXT_UPDATE: u64 = 4945931736;
+
+    // stand-in for the responder of the ca, answers every request with the same response
+    fn responder_stand_in(status: StatusCode, der: &'static [u8]) -> String {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                let mut res = Response::new(Body::from(der));
+                *res.status_mut() = status;
+                Ok::<_, Infallible>(res)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        url
+    }
+
+    async fn facade_with_cert() -> InMemoryFacade {
+        let facade = InMemoryFacade::default();
+        let mut cert = facade.start_cert().await.unwrap().unwrap();
+        cert.cert = Some(CERT.to_owned());
+        cert.private = Some(PRIVATE.to_owned());
+        facade.stop_cert(&mut cert).await.unwrap();
+
+        facade
+    }
+
+    #[test]
+    fn parse_staple() {
+        let staple = Staple::parse(CERT, GOOD).unwrap();
+        assert_eq!(THIS_UPDATE, staple.this_update);
+        assert_eq!(NEXT_UPDATE, staple.next_update);
+        assert_eq!(
+            THIS_UPDATE + (NEXT_UPDATE - THIS_UPDATE) / 2,
+            staple.refresh_time()
+        );
+
+        let error = Staple::parse(CERT, REVOKED).unwrap_err();
+        assert_eq!("OCSP cert status is 1", error.to_string());
+        assert!(Staple::parse(CERT, b"WRONG").is_err());
+
+        // tests/ca.crt is a single self signed cert
+        let other = include_str!("../tests/ca.crt");
+        let error = Staple::parse(other, GOOD).unwrap_err();
+        assert_eq!("Cert chain has no issuer", error.to_string());
+    }
+
+    #[test]
+    fn parse_responder() {
+        assert_eq!(
+            Some("http://ocsp.example.com".to_owned()),
+            responder(CERT).unwrap()
+        );
+        assert_eq!(None, responder(include_str!("../tests/ca.crt")).unwrap());
+    }
+
+    #[tokio::test]
+    async fn refresh_stores_response() {
+        let facade = facade_with_cert().await;
+        let stapler = OcspStapler::new(facade.clone());
+        let mut changes = facade.subscribe_certs();
+
+        let cert = facade.first_cert().await.unwrap().unwrap();
+        let responder = responder_stand_in(StatusCode::OK, GOOD);
+        stapler.refresh(&cert, &responder).await.unwrap();
+
+        let cert = facade.first_cert().await.unwrap().unwrap();
+        assert_eq!(Some(GOOD), cert.ocsp.as_deref());
+        assert_eq!(cert.id, changes.recv().await.unwrap());
+
+        // the response is kept until half of its validity passed
+        assert_eq!(Refresh::NotDue, stapler.manage().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refresh_rejects_bad_responses() {
+        let facade = facade_with_cert().await;
+        let stapler = OcspStapler::new(facade.clone());
+        let cert = facade.first_cert().await.unwrap().unwrap();
+
+        let revoked = responder_stand_in(StatusCode::OK, REVOKED);
+        assert!(stapler.refresh(&cert, &revoked).await.is_err());
+        let unavailable = responder_stand_in(StatusCode::SERVICE_UNAVAILABLE, b"");
+        let error = stapler.refresh(&cert, &unavailable).await.unwrap_err();
+        assert!(error.to_string().ends_with("503 Service Unavailable"));
+
+        let cert = facade.first_cert().await.unwrap().unwrap();
+        assert_eq!(None, cert.ocsp);
+    }
+
+    #[tokio::test]
+    async fn renewed_cert_drops_response() {
+        let facade = facade_with_cert().await;
+        let stapler = OcspStapler::new(facade.clone());
+        let mut cert = facade.first_cert().await.unwrap().unwrap();
+
+        // another replica renewed the cert while the response was fetched
+        let mut renewed = cert.clone();
+        renewed.cert = Some(include_str!("../tests/ca.crt").to_owned());
+        facade.update_cert(&renewed).await.unwrap();
+
+        let responder = responder_stand_in(StatusCode::OK, GOOD);
+        stapler.refresh(&cert, &responder).await.unwrap();
+        cert = facade.first_cert().await.unwrap().unwrap();
+        assert_eq!(None, cert.ocsp);
+    }
+
+    #[tokio::test]
+    async fn file_responses_are_kept_in_memory() {
+        let stapler = FileStapler::new();
+        let mut ocsp = None;
+
+        let unavailable = responder_stand_in(StatusCode::SERVICE_UNAVAILABLE, b"");
+        assert!(stapler
+            .refresh_file(CERT, &unavailable, &mut ocsp)
+            .await
+            .is_err());
+        assert_eq!(None, ocsp);
+
+        let responder = responder_stand_in(StatusCode::OK, GOOD);
+        stapler
+            .refresh_file(CERT, &responder, &mut ocsp)
+            .await
+            .unwrap();
+        assert_eq!(Some(GOOD), ocsp.as_deref());
+        assert_eq!(
+            Refresh::NotDue,
+            stapler.manage(CERT, &mut ocsp).await.unwrap()
+        );
+
+        // nothing changes while the response is not due
+        assert!(
+            !stapler
+                .refresh(&[CERT], std::slice::from_mut(&mut ocsp))
+                .await
+        );
+        assert_eq!(Some(GOOD), ocsp.as_deref());
+    }
+}
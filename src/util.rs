@@ -2,7 +2,7 @@ use anyhow::Error;
 use std::any::Any;
 use std::error::Error as StdError;
 use std::fmt::{Debug, Display};
-use std::io::{Error as IoError, ErrorKind};
+use std::io::Error as IoError;
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
@@ -57,13 +57,13 @@ where
         // try to downcast the anyhow error into an IoError, same as before
         let err = match err.downcast::<IoError>() {
             Ok(err) => err,
-            Err(err) => IoError::new(ErrorKind::Other, err),
+            Err(err) => IoError::other(err),
         };
         return err.into();
     }
 
     // if all else fails use IoError::new which takes anything that can be turned into a boxed error
-    IoError::new(ErrorKind::Other, err.unwrap()).into()
+    IoError::other(err.unwrap()).into()
 }
 
 pub(crate) fn uuid() -> String {
@@ -79,6 +79,30 @@ mod tests {
 
     use super::{error, now, to_i64, to_u64, uuid};
 
+    // stand-in for an error type with a From<IoError> implementation
+    #[derive(Debug)]
+    enum TestError {
+        Io(IoError),
+        Other(String),
+    }
+
+    impl From<IoError> for TestError {
+        fn from(err: IoError) -> Self {
+            TestError::Io(err)
+        }
+    }
+
+    impl std::fmt::Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                TestError::Io(err) => write!(f, "{}", err),
+                TestError::Other(err) => f.write_str(err),
+            }
+        }
+    }
+
+    impl std::error::Error for TestError {}
+
     const NUMBER_1: u64 = 2323;
     const NUMBER_2: u64 = 940329402394;
     #[test]
@@ -113,22 +137,22 @@ mod tests {
     #[test]
     #[should_panic]
     fn should_panic_extract_error() {
-        extract_error(acme_lib::Error::Other("Test".to_owned()));
+        extract_error(TestError::Other("Test".to_owned()));
     }
 
-    fn extract_error(err: acme_lib::Error) -> IoError {
+    fn extract_error(err: TestError) -> IoError {
         match err {
-            acme_lib::Error::Io(err) => err,
+            TestError::Io(err) => err,
             _ => panic!("Cannot match err"),
         }
     }
 
     #[test]
     fn self_error_works() {
-        let expected = acme_lib::Error::Other("Test".to_owned());
+        let expected = TestError::Other("Test".to_owned());
 
-        let actual = acme_lib::Error::Other("Test".to_owned());
-        let actual: acme_lib::Error = error(actual);
+        let actual = TestError::Other("Test".to_owned());
+        let actual: TestError = error(actual);
 
         assert_eq!(format!("{:?}", expected), format!("{:?}", actual));
         assert_eq!(format!("{}", expected), format!("{}", actual));
@@ -147,10 +171,10 @@ mod tests {
 
     #[test]
     fn anyhow_works() {
-        let expected = acme_lib::Error::Other("Test".to_owned());
+        let expected = TestError::Other("Test".to_owned());
 
-        let actual = Error::new(acme_lib::Error::Other("Test".to_owned()));
-        let actual: acme_lib::Error = error(actual);
+        let actual = Error::new(TestError::Other("Test".to_owned()));
+        let actual: TestError = error(actual);
 
         assert_eq!(format!("{:?}", expected), format!("{:?}", actual));
         assert_eq!(format!("{}", expected), format!("{}", actual));
@@ -169,7 +193,7 @@ mod tests {
 
     #[test]
     fn anyhow_error_works() {
-        let expected = IoError::new(ErrorKind::Other, anyhow!("Test"));
+        let expected = IoError::other(anyhow!("Test"));
 
         let actual = extract_error(error(anyhow!("Test")));
 
@@ -179,7 +203,7 @@ mod tests {
 
     #[test]
     fn error_works() {
-        let expected = IoError::new(ErrorKind::Other, "Test");
+        let expected = IoError::other("Test");
 
         let actual = extract_error(error("Test"));
 
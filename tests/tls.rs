@@ -10,7 +10,7 @@ use tokio_rustls::webpki::{
 };
 use tokio_rustls::TlsConnector;
 
-use acme_dns_rust::api::tls;
+use acme_dns_rust::api::tls::{self, DatabaseCerts, TlsConfig};
 use acme_dns_rust::facade::{Cert, CertFacade, InMemoryFacade, State};
 use acme_dns_rust::util::{now, to_i64};
 use rustls::internal::pemfile;
@@ -54,11 +54,15 @@ async fn test() {
         // todo: wrong files just to fix compilation
         cert: Some(include_str!("./ca.crt").to_owned()),
         private: Some(include_str!("./ca.key").to_owned()),
+        ocsp: None,
         domain: "acme-dns-rust.com".to_owned(),
     };
 
     let facade = InMemoryFacade::default();
     facade.create_cert(&cert).await.unwrap();
+    let config = TlsConfig::new();
+    let mut certs = DatabaseCerts::new(facade, Arc::clone(&config));
+    certs.refresh().await.unwrap();
 
     let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = server.local_addr().unwrap();
@@ -66,7 +70,7 @@ async fn test() {
     let server_future = tokio::spawn(async move {
         let (server, _) = server.accept().await.unwrap();
         let server = stream::iter(vec![Ok(future::ready(Ok(server)))]);
-        let mut acceptor = tls::wrap(server, facade);
+        let mut acceptor = tls::wrap(server, config);
 
         let mut conn = acceptor.next().await.unwrap().unwrap().await.unwrap();
         let mut actual = String::new();
@@ -86,7 +90,7 @@ async fn test() {
         let domain = DNSNameRef::try_from_ascii_str("acme-dns-rust.com").unwrap();
         let mut conn = connector.connect(domain, client).await.unwrap();
         conn.write_all("Test".as_ref()).await.unwrap();
-        conn.write(&[]).await.unwrap();
+        conn.shutdown().await.unwrap();
     });
 
     tokio::try_join!(server_future, client_future).unwrap();